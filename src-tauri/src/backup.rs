@@ -0,0 +1,289 @@
+use crate::error::{Error, Result};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of backups kept of each config file
+pub const BACKUP_COUNT: usize = 10;
+
+/// Folder next to the config that holds the backups
+const BACKUP_DIR: &str = "backups";
+
+/// Copy of a config file taken before it was overwritten
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backup {
+    pub path: PathBuf,
+    /// When the backup was taken, e.g. "2026-10-18 09:30:00 UTC"
+    pub label: String,
+}
+
+impl Backup {
+    pub fn file_name(&self) -> String {
+        file_name(&self.path)
+    }
+}
+
+/// Replace the content of `path`, keeping a backup of what was there before
+pub fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    back_up(path)?;
+    write_atomic(path, contents)
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it over `path`.
+/// A crash or a full disk leaves either the old or the new file, never a truncated one.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let temp_path = dir.join(format!(".{}.tmp", file_name(path)));
+
+    if let Err(e) = write_temp_file(&temp_path, path, contents) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    sync_dir(dir)
+}
+
+fn write_temp_file(temp_path: &Path, path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = fs::File::create(temp_path).map_err(|e| Error::io("create", temp_path, e))?;
+
+    // The config holds secrets, keep the permissions of the file being replaced
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(temp_path, metadata.permissions())
+            .map_err(|e| Error::io("set permissions of", temp_path, e))?;
+    }
+
+    file.write_all(contents)
+        .map_err(|e| Error::io("write", temp_path, e))?;
+    file.sync_all()
+        .map_err(|e| Error::io("sync", temp_path, e))?;
+
+    fs::rename(temp_path, path).map_err(|e| Error::io("replace", path, e))
+}
+
+/// Make the rename itself survive a crash. Directories can only be synced on Unix.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)
+        .and_then(|dir_file| dir_file.sync_all())
+        .map_err(|e| Error::io("sync", dir, e))
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Copy `path` into the backup folder and delete the backups beyond `BACKUP_COUNT`.
+/// Missing and empty files have nothing worth keeping.
+fn back_up(path: &Path) -> Result<()> {
+    if !fs::metadata(path).is_ok_and(|metadata| metadata.len() > 0) {
+        return Ok(());
+    }
+
+    let dir = backup_dir(path);
+    fs::create_dir_all(&dir).map_err(|e| Error::io("create backup folder", &dir, e))?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let backup_path = dir.join(backup_name(path, &format_timestamp(now)));
+    // Of several saves within a second, the backup keeps the content before the first
+    if !backup_path.exists() {
+        fs::copy(path, &backup_path).map_err(|e| Error::io("back up", path, e))?;
+    }
+
+    for backup in list_backups(path)?.into_iter().skip(BACKUP_COUNT) {
+        fs::remove_file(&backup.path).map_err(|e| Error::io("remove backup", &backup.path, e))?;
+    }
+
+    Ok(())
+}
+
+/// Backups of `path`, newest first
+pub fn list_backups(path: &Path) -> Result<Vec<Backup>> {
+    let dir = backup_dir(path);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::io("read backup folder", &dir, e)),
+    };
+
+    let mut backups: Vec<Backup> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|backup_path| {
+            let timestamp = backup_timestamp(path, &file_name(&backup_path))?;
+            Some(Backup {
+                label: format_label(&timestamp),
+                path: backup_path,
+            })
+        })
+        .collect();
+    // The timestamps sort in the same order as the times they stand for
+    backups.sort_by(|a, b| b.path.cmp(&a.path));
+
+    Ok(backups)
+}
+
+/// Put the content of `backup` back in place of `path`.
+/// The current content is backed up first, so a restore can be undone.
+pub fn restore(path: &Path, backup: &Path) -> Result<()> {
+    let contents = fs::read(backup).map_err(|e| Error::io("read backup", backup, e))?;
+    write_file(path, &contents)
+}
+
+/// Delete all backups of `path`, e.g. of a plaintext config once it was encrypted
+pub fn remove_backups(path: &Path) -> Result<()> {
+    for backup in list_backups(path)? {
+        fs::remove_file(&backup.path).map_err(|e| Error::io("remove backup", &backup.path, e))?;
+    }
+    Ok(())
+}
+
+fn backup_dir(path: &Path) -> PathBuf {
+    path.parent().unwrap_or(Path::new(".")).join(BACKUP_DIR)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Text before and after the timestamp in the names of the backups of `path`
+fn backup_affixes(path: &Path) -> (String, String) {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (format!("{}-", stem), extension)
+}
+
+/// "config-20261018-093000.toml" for a backup of "config.toml"
+fn backup_name(path: &Path, timestamp: &str) -> String {
+    let (prefix, suffix) = backup_affixes(path);
+    format!("{}{}{}", prefix, timestamp, suffix)
+}
+
+/// The timestamp in `name` if it is the name of a backup of `path`
+fn backup_timestamp(path: &Path, name: &str) -> Option<String> {
+    let (prefix, suffix) = backup_affixes(path);
+    let timestamp = name.strip_prefix(&prefix)?.strip_suffix(&suffix)?;
+
+    let is_timestamp = timestamp.len() == 15
+        && timestamp
+            .char_indices()
+            .all(|(i, c)| if i == 8 { c == '-' } else { c.is_ascii_digit() });
+    is_timestamp.then(|| timestamp.to_string())
+}
+
+/// UTC date and time of a Unix timestamp as "YYYYMMDD-HHMMSS"
+fn format_timestamp(secs: u64) -> String {
+    // Civil date from days since the epoch, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = secs / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    let time = secs % 86400;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// "20261018-093000" as "2026-10-18 09:30:00 UTC"
+fn format_label(timestamp: &str) -> String {
+    format!(
+        "{}-{}-{} {}:{}:{} UTC",
+        &timestamp[0..4],
+        &timestamp[4..6],
+        &timestamp[6..8],
+        &timestamp[9..11],
+        &timestamp[11..13],
+        &timestamp[13..15]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "19700101-000000");
+        assert_eq!(format_timestamp(1234567890), "20090213-233130");
+        // Leap day
+        assert_eq!(format_timestamp(1709210096), "20240229-123456");
+        assert_eq!(format_label("20090213-233130"), "2009-02-13 23:31:30 UTC");
+    }
+
+    #[test]
+    fn test_backup_timestamp() {
+        let path = Path::new("/config/config.toml");
+        assert_eq!(
+            backup_timestamp(path, "config-20090213-233130.toml").as_deref(),
+            Some("20090213-233130")
+        );
+        assert_eq!(backup_timestamp(path, "config-20090213-233130.vault"), None);
+        assert_eq!(backup_timestamp(path, "config-latest.toml"), None);
+        assert_eq!(backup_timestamp(path, "other-20090213-233130.toml"), None);
+    }
+
+    #[test]
+    fn test_write_file_keeps_backups() {
+        let dir = std::env::temp_dir().join("otp_bar_test_backup");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+
+        // Nothing to back up yet
+        write_file(&path, b"first").unwrap();
+        assert!(list_backups(&path).unwrap().is_empty());
+
+        write_file(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        let backups = list_backups(&path).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(fs::read(&backups[0].path).unwrap(), b"first");
+        assert!(!dir.join(".config.toml.tmp").exists());
+
+        restore(&path, &backups[0].path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+
+        // Only the newest backups are kept
+        remove_backups(&path).unwrap();
+        let backup_dir = backup_dir(&path);
+        for i in 0..BACKUP_COUNT + 2 {
+            let name = backup_name(&path, &format_timestamp(i as u64));
+            fs::write(backup_dir.join(name), "old").unwrap();
+        }
+        write_file(&path, b"third").unwrap();
+        let backups = list_backups(&path).unwrap();
+        assert_eq!(backups.len(), BACKUP_COUNT);
+        assert_eq!(fs::read(&backups[0].path).unwrap(), b"first");
+        assert!(backups
+            .iter()
+            .all(|backup| backup.file_name() != "config-19700101-000000.toml"));
+
+        remove_backups(&path).unwrap();
+        assert!(list_backups(&path).unwrap().is_empty());
+        assert_eq!(fs::read(&path).unwrap(), b"third");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
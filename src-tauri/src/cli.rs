@@ -0,0 +1,368 @@
+use crate::config::{Config, ConfigStore, ImportPolicy, ImportedToken, TokenData, TokenType};
+use crate::otp::{generate_hotp, generate_otp, get_otp_remaining_time, Algorithm};
+use crate::{open_config_store, qr, secret};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Command-line access to the tokens of OTP Bar. Output is JSON.
+#[derive(Debug, Parser)]
+#[command(name = "otp-bar", version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the configured tokens
+    List,
+    /// Print the current code of a token. HOTP tokens advance their counter.
+    Code { name: String },
+    /// Add a TOTP token with a base32 secret
+    Add {
+        name: String,
+        secret: String,
+        #[arg(long)]
+        issuer: Option<String>,
+        /// Replace a token with the same name
+        #[arg(long)]
+        force: bool,
+    },
+    /// Remove a token
+    Remove { name: String },
+    /// Import tokens from QR code images
+    Import {
+        #[arg(required = true)]
+        images: Vec<PathBuf>,
+        /// What to do with a token whose name is taken: skip, rename or overwrite.
+        /// Defaults to `import_policy` in the settings.
+        #[arg(long)]
+        on_conflict: Option<ImportPolicy>,
+    },
+    /// Export tokens as Google Authenticator migration QR codes
+    Export {
+        dir: PathBuf,
+        /// Tokens to export, all of them if none are given
+        names: Vec<String>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct TokenSummary {
+    name: String,
+    issuer: Option<String>,
+    #[serde(rename = "type")]
+    token_type: TokenType,
+    algorithm: Algorithm,
+    digits: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    period: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    counter: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct CodeOutput {
+    name: String,
+    code: String,
+    /// Seconds until a TOTP code changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining: Option<u64>,
+    /// Counter an HOTP code was generated with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    counter: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportOutput {
+    tokens: Vec<ImportedToken>,
+    warnings: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportOutput {
+    files: Vec<PathBuf>,
+    skipped: Vec<String>,
+}
+
+/// Whether the binary was started from a terminal with a subcommand rather than
+/// as the menu bar app. Old macOS versions pass `-psn_*` to apps started by Finder.
+pub fn is_cli_invocation() -> bool {
+    std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| !arg.to_string_lossy().starts_with("-psn_"))
+}
+
+/// Run the command line interface and return the process exit code
+pub fn run() -> i32 {
+    // Prints help or usage errors and exits with status 2 on its own
+    let cli = Cli::parse();
+
+    match execute(cli.command) {
+        Ok(output) => {
+            println!("{}", output);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", serde_json::json!({ "error": e }));
+            1
+        }
+    }
+}
+
+fn execute(command: Command) -> Result<serde_json::Value, String> {
+    let store = open_config_store()?;
+
+    let output = match command {
+        Command::List => to_json(list_tokens(&store.load()?))?,
+        Command::Code { name } => to_json(token_code(&store, &name)?)?,
+        Command::Add {
+            name,
+            secret,
+            issuer,
+            force,
+        } => {
+            crate::otp::decode_secret(&secret)?;
+
+            store.update(|config| {
+                if !force && config.get_token_data(&name).is_some() {
+                    return Err(format!(
+                        "Token '{}' already exists, use --force to replace it",
+                        name
+                    ));
+                }
+                config.add_token(name.clone(), secret.clone());
+                if let Some(token) = config.tokens.get_mut(&name) {
+                    token.issuer = issuer.clone();
+                }
+                Ok(())
+            })?;
+            serde_json::json!({ "added": name })
+        }
+        Command::Remove { name } => {
+            store.update(|config| {
+                config
+                    .remove_token(&name)
+                    .map(|_| ())
+                    .ok_or_else(|| format!("Token '{}' not found", name))
+            })?;
+            serde_json::json!({ "removed": name })
+        }
+        Command::Import {
+            images,
+            on_conflict,
+        } => {
+            let mut tokens = Vec::new();
+            let mut warnings = Vec::new();
+            for image in &images {
+                match qr::parse_qr_and_extract_tokens(&image.to_string_lossy()) {
+                    Ok(found) => {
+                        tokens.extend(found.tokens);
+                        warnings.extend(
+                            found
+                                .failures
+                                .iter()
+                                .map(|failure| format!("{}: {}", image.display(), failure)),
+                        );
+                    }
+                    Err(e) => warnings.push(format!("{}: {}", image.display(), e)),
+                }
+            }
+            if tokens.is_empty() {
+                return Err(format!("No tokens found ({})", warnings.join("; ")));
+            }
+
+            let report = store.update(|config| {
+                let policy = on_conflict.unwrap_or(config.settings.import_policy);
+                Ok::<_, String>(crate::merge_tokens(config, &tokens, policy))
+            })?;
+
+            to_json(ImportOutput {
+                tokens: report.tokens,
+                warnings,
+            })?
+        }
+        Command::Export { dir, names } => {
+            let config = store.load()?;
+            let names = if names.is_empty() {
+                config.list_token_names()
+            } else {
+                names
+            };
+
+            let export =
+                qr::export_config_tokens(&config, &names, &dir, |_, token| resolve(token))?;
+            to_json(ExportOutput {
+                files: export.files,
+                skipped: export.skipped,
+            })?
+        }
+    };
+
+    Ok(output)
+}
+
+fn to_json<T: Serialize>(value: T) -> Result<serde_json::Value, String> {
+    serde_json::to_value(value).map_err(|e| format!("Failed to serialize output: {}", e))
+}
+
+/// Fill in the secret of a token that refers to an external source
+fn resolve(token: &TokenData) -> Result<TokenData, String> {
+    let mut token = token.clone();
+    if secret::is_external(&token) {
+        token.secret = secret::resolve_secret(&token)?;
+    }
+    Ok(token)
+}
+
+fn list_tokens(config: &Config) -> Vec<TokenSummary> {
+    config
+        .list_token_names()
+        .into_iter()
+        .filter_map(|name| {
+            let token = config.get_token_data(&name)?;
+            let is_totp = token.token_type == TokenType::Totp;
+            Some(TokenSummary {
+                issuer: token.issuer.clone(),
+                token_type: token.token_type,
+                algorithm: token.algorithm(),
+                digits: token.digits(),
+                period: is_totp.then(|| token.period()),
+                counter: (!is_totp).then(|| token.counter.unwrap_or(0)),
+                name,
+            })
+        })
+        .collect()
+}
+
+fn token_code(store: &ConfigStore, name: &str) -> Result<CodeOutput, String> {
+    let config = store.load()?;
+    let token = config
+        .get_token_data(name)
+        .ok_or_else(|| format!("Token '{}' not found", name))?;
+    let token = resolve(token)?;
+
+    match token.token_type {
+        TokenType::Totp => {
+            let code = generate_otp(
+                &token.secret,
+                token.algorithm(),
+                token.digits(),
+                token.period(),
+            )?;
+            Ok(CodeOutput {
+                name: name.to_string(),
+                code,
+                remaining: get_otp_remaining_time(token.period()),
+                counter: None,
+            })
+        }
+        TokenType::Hotp => {
+            // Same as clicking the token in the menu: the counter is only
+            // handed out once it has been saved
+            let (code, counter) = store.update(|config| {
+                let counter = config.advance_counter(name)?;
+                let code =
+                    generate_hotp(&token.secret, token.algorithm(), token.digits(), counter)?;
+                Ok::<_, String>((code, counter))
+            })?;
+
+            Ok(CodeOutput {
+                name: name.to_string(),
+                code,
+                remaining: None,
+                counter: Some(counter),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_subcommands() {
+        let cli = Cli::try_parse_from(["otp-bar", "code", "GitHub"]).unwrap();
+        assert!(matches!(cli.command, Command::Code { name } if name == "GitHub"));
+
+        let cli = Cli::try_parse_from([
+            "otp-bar",
+            "add",
+            "GitHub",
+            "JBSWY3DPEHPK3PXP",
+            "--issuer",
+            "GitHub",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Add {
+                issuer: Some(_),
+                force: false,
+                ..
+            }
+        ));
+
+        let cli =
+            Cli::try_parse_from(["otp-bar", "import", "qr.png", "--on-conflict", "skip"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Import {
+                on_conflict: Some(ImportPolicy::Skip),
+                ..
+            }
+        ));
+        assert!(
+            Cli::try_parse_from(["otp-bar", "import", "qr.png", "--on-conflict", "merge"]).is_err()
+        );
+
+        let cli = Cli::try_parse_from(["otp-bar", "export", "out", "GitHub", "VPN"]).unwrap();
+        assert!(matches!(cli.command, Command::Export { names, .. } if names == ["GitHub", "VPN"]));
+
+        assert!(Cli::try_parse_from(["otp-bar", "import"]).is_err());
+        assert!(Cli::try_parse_from(["otp-bar", "unknown"]).is_err());
+    }
+
+    #[test]
+    fn test_hotp_code_advances_counter() {
+        let temp_dir = std::env::temp_dir().join("otp_bar_test_cli_hotp");
+        let _ = std::fs::remove_dir_all(&temp_dir);
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        let path = temp_dir.join("config.toml");
+        let mut config = Config::default();
+        config.tokens.insert(
+            "vpn".to_string(),
+            TokenData {
+                secret: "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".to_string(),
+                token_type: TokenType::Hotp,
+                counter: Some(1),
+                ..Default::default()
+            },
+        );
+        config.save(&path).unwrap();
+        let store = ConfigStore::Plain(path.clone());
+
+        // RFC 4226 test vectors for counters 1 and 2
+        let output = token_code(&store, "vpn").unwrap();
+        assert_eq!(output.code, "287082");
+        assert_eq!(output.counter, Some(1));
+        assert_eq!(token_code(&store, "vpn").unwrap().code, "359152");
+
+        let summary = list_tokens(&store.load().unwrap());
+        assert_eq!(summary[0].counter, Some(3));
+        assert_eq!(summary[0].period, None);
+
+        assert!(token_code(&store, "missing").is_err());
+
+        // Also removes the backups and the lock file next to the config
+        let _ = std::fs::remove_dir_all(&temp_dir);
+    }
+}
@@ -1,56 +1,400 @@
+use crate::backup;
+use crate::error::{Error, Result};
+use crate::lock;
+use crate::otp::{Algorithm, DEFAULT_DIGITS, DEFAULT_PERIOD};
+use crate::vault::{self, VaultKey};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml_edit::{DocumentMut, Item, Table, TableLike, Value};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    #[default]
+    Totp,
+    Hotp,
+}
+
+impl TokenType {
+    fn is_totp(&self) -> bool {
+        *self == TokenType::Totp
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
 pub struct TokenData {
+    /// Base32 secret. Left empty when one of the `secret_*` sources is used instead.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub secret: String,
+    /// Command printing the secret, e.g. `["pass", "show", "totp/github"]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_command: Option<Vec<String>>,
+    /// File containing the secret
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_file: Option<String>,
+    /// Environment variable containing the secret
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_env: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "TokenType::is_totp")]
+    pub token_type: TokenType,
+    /// Moving factor of an HOTP token, i.e. the counter used for the next code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counter: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<Algorithm>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digits: Option<u32>,
+    /// TOTP period in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub priority: Option<i32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+impl TokenData {
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm.unwrap_or_default()
+    }
+
+    pub fn digits(&self) -> u32 {
+        self.digits.unwrap_or(DEFAULT_DIGITS)
+    }
+
+    pub fn period(&self) -> u64 {
+        self.period.unwrap_or(DEFAULT_PERIOD)
+    }
+}
+
+/// What copying a TOTP code does when the code is about to expire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NearExpiry {
+    /// Wait for the next period and copy the fresh code
+    #[default]
+    Wait,
+    /// Copy the code of the next period right away
+    Next,
+}
+
+impl NearExpiry {
+    fn is_wait(&self) -> bool {
+        *self == NearExpiry::Wait
+    }
+}
+
+/// What an import does with a token whose name is already taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportPolicy {
+    /// Keep the existing token and leave out the imported one
+    Skip,
+    /// Add the imported token under a free name, e.g. "GitHub (2)"
+    #[default]
+    Rename,
+    /// Replace the existing token
+    Overwrite,
+}
+
+impl ImportPolicy {
+    fn is_rename(&self) -> bool {
+        *self == ImportPolicy::Rename
+    }
+}
+
+impl std::str::FromStr for ImportPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(ImportPolicy::Skip),
+            "rename" => Ok(ImportPolicy::Rename),
+            "overwrite" => Ok(ImportPolicy::Overwrite),
+            _ => Err(Error::InvalidParameter(format!(
+                "Unsupported import policy: {}, use skip, rename or overwrite",
+                s
+            ))),
+        }
+    }
+}
+
+/// What happened to a token during an import
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum ImportOutcome {
+    Added,
+    /// Added under another name because `from` was taken
+    Renamed {
+        from: String,
+    },
+    /// Replaced the token with the same name
+    Overwritten,
+    /// Left out because the name is taken
+    Skipped,
+    /// Left out because the config already has a token with the same secret
+    Duplicate {
+        existing: String,
+    },
+}
+
+/// A token of an import and what happened to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportedToken {
+    /// Name the token has in the config, or would have had if it was left out
+    pub name: String,
+    #[serde(flatten)]
+    pub outcome: ImportOutcome,
+}
+
+impl std::fmt::Display for ImportedToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.outcome {
+            ImportOutcome::Added => write!(f, "{}: added", self.name),
+            ImportOutcome::Renamed { from } => {
+                write!(f, "{}: added, \"{}\" was already taken", self.name, from)
+            }
+            ImportOutcome::Overwritten => write!(f, "{}: replaced the existing token", self.name),
+            ImportOutcome::Skipped => write!(f, "{}: skipped, the name is taken", self.name),
+            ImportOutcome::Duplicate { existing } if *existing == self.name => {
+                write!(f, "{}: skipped, already in the config", self.name)
+            }
+            ImportOutcome::Duplicate { existing } => write!(
+                f,
+                "{}: skipped, already in the config as \"{}\"",
+                self.name, existing
+            ),
+        }
+    }
+}
+
+/// Result of importing several tokens, in the order they were imported
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ImportReport {
+    pub tokens: Vec<ImportedToken>,
+}
+
+impl ImportReport {
+    /// Number of tokens that were written to the config
+    pub fn written(&self) -> usize {
+        self.tokens
+            .iter()
+            .filter(|token| {
+                !matches!(
+                    token.outcome,
+                    ImportOutcome::Skipped | ImportOutcome::Duplicate { .. }
+                )
+            })
+            .count()
+    }
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Imported {} of {} token(s).",
+            self.written(),
+            self.tokens.len()
+        )?;
+        for token in &self.tokens {
+            write!(f, "\n{}", token)?;
+        }
+        Ok(())
+    }
+}
+
+/// App-wide options from the `[settings]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct Settings {
+    /// Clear a copied code from the clipboard after this many seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clipboard_clear_seconds: Option<u64>,
+    /// Copying with this many seconds or less left does not hand out the current code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiry_threshold_seconds: Option<u64>,
+    #[serde(default, skip_serializing_if = "NearExpiry::is_wait")]
+    pub near_expiry: NearExpiry,
+    /// Command to open the config with, where `{file}`, `{line}` and `{column}`
+    /// are replaced with the place to edit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub editor_command: Option<Vec<String>>,
+    /// What importing a token with a name that is already taken does
+    #[serde(default, skip_serializing_if = "ImportPolicy::is_rename")]
+    pub import_policy: ImportPolicy,
+}
+
+impl Settings {
+    fn is_default(&self) -> bool {
+        *self == Settings::default()
+    }
+
+    /// The editor command with its placeholders filled in, `None` if none is set
+    pub fn editor_command_at(&self, file: &str, line: usize, column: usize) -> Option<Vec<String>> {
+        let command = self
+            .editor_command
+            .as_ref()
+            .filter(|command| !command.is_empty())?;
+        Some(
+            command
+                .iter()
+                .map(|arg| {
+                    arg.replace("{file}", file)
+                        .replace("{line}", &line.to_string())
+                        .replace("{column}", &column.to_string())
+                })
+                .collect(),
+        )
+    }
+
+    /// What copying does with `remaining` seconds left, `None` to copy the current code
+    pub fn near_expiry_action(&self, remaining: u64) -> Option<NearExpiry> {
+        match self.expiry_threshold_seconds {
+            Some(threshold) if remaining <= threshold => Some(self.near_expiry),
+            _ => None,
+        }
+    }
+
+    /// How long a copied code stays in the clipboard, `None` to keep it
+    pub fn clipboard_clear_delay(&self) -> Option<Duration> {
+        self.clipboard_clear_seconds
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
 pub struct Config {
+    #[serde(default, skip_serializing_if = "Settings::is_default")]
+    pub settings: Settings,
     #[serde(default)]
     pub tokens: HashMap<String, TokenData>,
 }
 
 impl Config {
-    fn ensure_config_exists(config_path: &PathBuf) -> Result<(), String> {
+    fn ensure_config_exists(config_path: &PathBuf) -> Result<()> {
         if !config_path.exists() {
-            fs::create_dir_all(
-                config_path
-                    .parent()
-                    .ok_or("Failed to get parent directory of config path")?,
-            )
-            .map_err(|e| format!("Failed to create config directory: {}", e))?;
+            if let Some(config_dir) = config_path.parent() {
+                fs::create_dir_all(config_dir)
+                    .map_err(|e| Error::io("create config directory", config_dir, e))?;
+            }
             fs::write(config_path, "")
-                .map_err(|e| format!("Failed to create empty config file: {}", e))?;
+                .map_err(|e| Error::io("create empty config file", config_path, e))?;
         }
-        println!("Config file exists at {:?}", config_path);
         Ok(())
     }
 
-    pub fn load(config_path: &PathBuf) -> Result<Self, String> {
-        Self::ensure_config_exists(config_path)
-            .map_err(|e| format!("Failed to ensure config exists: {}", e))?;
+    pub fn load(config_path: &PathBuf) -> Result<Self> {
+        Self::parse(&Self::read_content(config_path)?)
+    }
 
-        let content = fs::read_to_string(config_path)
-            .map_err(|e| format!("Failed to read config file: {}", e))?;
+    fn read_content(config_path: &PathBuf) -> Result<String> {
+        Self::ensure_config_exists(config_path)?;
+
+        fs::read_to_string(config_path).map_err(|e| Error::io("read config file", config_path, e))
+    }
 
-        toml::from_str(&content).map_err(|e| format!("Failed to parse TOML config: {}", e))
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
     }
 
-    pub fn save(&self, config_path: &PathBuf) -> Result<(), String> {
-        // Ensure parent directory exists
-        Self::ensure_config_exists(config_path)
-            .map_err(|e| format!("Failed to ensure config exists: {}", e))?;
+    fn from_plaintext(plaintext: &[u8]) -> Result<Self> {
+        Self::parse(std::str::from_utf8(plaintext)?)
+    }
+
+    /// Decrypt a vault file and parse the config inside it.
+    /// Returns the key needed to save the vault again.
+    pub fn load_encrypted(vault_path: &PathBuf, password: &str) -> Result<(Self, VaultKey)> {
+        let content = fs::read_to_string(vault_path)
+            .map_err(|e| Error::io("read vault file", vault_path, e))?;
+        let (plaintext, key) = vault::decrypt(&content, password)?;
+
+        Ok((Self::from_plaintext(&plaintext)?, key))
+    }
 
-        let content = toml::to_string_pretty(self)
-            .map_err(|e| format!("Failed to serialize config to TOML: {}", e))?;
+    pub fn save_encrypted(&self, vault_path: &Path, key: &VaultKey) -> Result<()> {
+        let content = toml::to_string_pretty(self)?;
+        let encrypted = vault::encrypt(content.as_bytes(), key)?;
 
-        fs::write(config_path, content).map_err(|e| format!("Failed to write config file: {}", e))
+        backup::write_file(vault_path, encrypted.as_bytes())
+    }
+
+    /// Write the config to `config_path`, keeping the comments and layout of the file
+    pub fn save(&self, config_path: &PathBuf) -> Result<()> {
+        let content = self.update_document(&Self::read_content(config_path)?)?;
+
+        backup::write_file(config_path, content.as_bytes())
+    }
+
+    /// Apply the config to the TOML text `content`. Only the settings and the
+    /// tokens that differ from `content` are rewritten, everything else is kept
+    /// as it was written, including comments and the order of the tokens.
+    fn update_document(&self, content: &str) -> Result<String> {
+        // Refuses to overwrite a file that is not a valid config
+        let previous = Self::parse(content)?;
+        let mut document: DocumentMut = content.parse()?;
+        let updated: DocumentMut = toml::to_string_pretty(self)?.parse()?;
+        // What the config wrote before the change. Keys missing from it, such as
+        // notes the user added by hand, are not the config's to remove.
+        let written: DocumentMut = toml::to_string_pretty(&previous)?.parse()?;
+
+        if previous.settings != self.settings {
+            // All default settings are not written at all
+            let empty = Item::Table(Table::new());
+            let settings = updated.get("settings").unwrap_or(&empty);
+            update_item(
+                document.as_table_mut(),
+                "settings",
+                settings,
+                written.get("settings"),
+            );
+            if document
+                .get("settings")
+                .and_then(Item::as_table_like)
+                .is_some_and(|settings| settings.is_empty())
+            {
+                document.remove("settings");
+            }
+        }
+
+        let mut implicit = Table::new();
+        implicit.set_implicit(true);
+        let (Some(tokens), Some(updated_tokens)) = (
+            document
+                .entry("tokens")
+                .or_insert(Item::Table(implicit))
+                .as_table_like_mut(),
+            updated.get("tokens").and_then(Item::as_table_like),
+        ) else {
+            return Ok(document.to_string());
+        };
+
+        for name in previous.tokens.keys() {
+            if !self.tokens.contains_key(name) {
+                tokens.remove(name);
+            }
+        }
+
+        // Sorted so new tokens are appended in a stable order
+        let mut names: Vec<&String> = self.tokens.keys().collect();
+        names.sort();
+        for name in names {
+            if previous.tokens.get(name) == self.tokens.get(name) {
+                continue;
+            }
+            if let Some(token) = updated_tokens.get(name) {
+                let written_token = written
+                    .get("tokens")
+                    .and_then(Item::as_table_like)
+                    .and_then(|tokens| tokens.get(name));
+                update_item(tokens, name, token, written_token);
+            }
+        }
+
+        Ok(document.to_string())
     }
 
     pub fn add_token(&mut self, name: String, secret: String) {
@@ -58,15 +402,48 @@ impl Config {
             name,
             TokenData {
                 secret,
-                priority: None,
+                ..Default::default()
             },
         );
     }
 
+    pub fn remove_token(&mut self, name: &str) -> Option<TokenData> {
+        self.tokens.remove(name)
+    }
+
     pub fn get_token(&self, name: &str) -> Option<&String> {
         self.tokens.get(name).map(|t| &t.secret)
     }
 
+    pub fn get_token_data(&self, name: &str) -> Option<&TokenData> {
+        self.tokens.get(name)
+    }
+
+    /// Consume the current counter of an HOTP token and advance it by one.
+    /// Returns the counter value to generate the code with.
+    pub fn advance_counter(&mut self, name: &str) -> Result<u64> {
+        let token = self
+            .tokens
+            .get_mut(name)
+            .ok_or_else(|| Error::TokenNotFound(name.to_string()))?;
+
+        if token.token_type != TokenType::Hotp {
+            return Err(Error::InvalidToken {
+                name: name.to_string(),
+                reason: "is not an HOTP token".to_string(),
+            });
+        }
+
+        let counter = token.counter.unwrap_or(0);
+        let next = counter.checked_add(1).ok_or_else(|| Error::InvalidToken {
+            name: name.to_string(),
+            reason: "has a counter that overflowed".to_string(),
+        })?;
+        token.counter = Some(next);
+
+        Ok(counter)
+    }
+
     pub fn list_token_names(&self) -> Vec<String> {
         let mut tokens_with_priority: Vec<(&String, i32)> = Vec::new();
         let mut tokens_without_priority: Vec<&String> = Vec::new();
@@ -96,6 +473,294 @@ impl Config {
 
         result
     }
+
+    /// Add an imported token, handling a taken name according to `policy`.
+    /// A token whose secret is already in the config is never added twice.
+    pub fn import_token(
+        &mut self,
+        name: String,
+        token: TokenData,
+        policy: ImportPolicy,
+    ) -> ImportedToken {
+        if let Some(existing) = self.find_same_secret(&token) {
+            return ImportedToken {
+                name,
+                outcome: ImportOutcome::Duplicate { existing },
+            };
+        }
+
+        let (name, outcome) = if !self.tokens.contains_key(&name) {
+            (name, ImportOutcome::Added)
+        } else {
+            match policy {
+                ImportPolicy::Skip => {
+                    return ImportedToken {
+                        name,
+                        outcome: ImportOutcome::Skipped,
+                    }
+                }
+                ImportPolicy::Overwrite => (name, ImportOutcome::Overwritten),
+                ImportPolicy::Rename => {
+                    (self.free_name(&name), ImportOutcome::Renamed { from: name })
+                }
+            }
+        };
+
+        self.tokens.insert(name.clone(), token);
+        ImportedToken { name, outcome }
+    }
+
+    /// Import several tokens at once, see `import_token`
+    pub fn import_tokens(
+        &mut self,
+        tokens: impl IntoIterator<Item = (String, TokenData)>,
+        policy: ImportPolicy,
+    ) -> ImportReport {
+        ImportReport {
+            tokens: tokens
+                .into_iter()
+                .map(|(name, token)| self.import_token(name, token, policy))
+                .collect(),
+        }
+    }
+
+    /// Name of a token with the same inline secret and type as `token`.
+    /// Secrets from commands, files or the environment are not compared.
+    fn find_same_secret(&self, token: &TokenData) -> Option<String> {
+        let secret = normalize_secret(&token.secret);
+        if secret.is_empty() {
+            return None;
+        }
+
+        let mut names: Vec<&String> = self
+            .tokens
+            .iter()
+            .filter(|(_, existing)| {
+                existing.token_type == token.token_type
+                    && normalize_secret(&existing.secret) == secret
+            })
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names.first().map(|name| name.to_string())
+    }
+
+    /// "`name` (2)", or the first higher number that is not taken yet
+    fn free_name(&self, name: &str) -> String {
+        (2..)
+            .map(|number| format!("{} ({})", name, number))
+            .find(|candidate| !self.tokens.contains_key(candidate))
+            .unwrap_or_else(|| name.to_string())
+    }
+}
+
+/// Secret in the form used for comparisons, ignoring case, padding and spaces
+fn normalize_secret(secret: &str) -> String {
+    secret
+        .chars()
+        .filter(|c| *c != '=' && !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+/// How often `ConfigStore::update` starts over when the file keeps changing underneath it
+const UPDATE_ATTEMPTS: usize = 3;
+
+/// Backend the config is loaded from and saved to
+#[derive(Debug, Clone)]
+pub enum ConfigStore {
+    /// Plaintext TOML file
+    Plain(PathBuf),
+    /// Encrypted vault, unlocked with the key derived from its password
+    Vault { path: PathBuf, key: VaultKey },
+}
+
+impl ConfigStore {
+    pub fn load(&self) -> Result<Config> {
+        Config::parse(&self.read_content()?)
+    }
+
+    /// The TOML text of the config, decrypted if it is in the vault
+    pub fn read_content(&self) -> Result<String> {
+        match self {
+            ConfigStore::Plain(path) => Config::read_content(path),
+            ConfigStore::Vault { path, key } => {
+                let content =
+                    fs::read_to_string(path).map_err(|e| Error::io("read vault file", path, e))?;
+                String::from_utf8(vault::decrypt_with_key(&content, key)?)
+                    .map_err(|e| Error::ConfigEncoding(e.utf8_error()))
+            }
+        }
+    }
+
+    /// Load the config, change it with `update` and save it again as one transaction.
+    ///
+    /// Other OTP Bar processes wait for the config lock in the meantime. Editors
+    /// do not know about that lock, so if the file was changed between loading
+    /// and saving, the update is applied again on top of the new content.
+    pub fn update<T, E>(
+        &self,
+        mut update: impl FnMut(&mut Config) -> std::result::Result<T, E>,
+    ) -> std::result::Result<T, E>
+    where
+        E: From<Error>,
+    {
+        let _lock = lock::lock_config(self.path())?;
+
+        for _ in 0..UPDATE_ATTEMPTS {
+            let content = self.read_content()?;
+            let mut config = Config::parse(&content)?;
+            let value = update(&mut config)?;
+
+            if self.read_content()? == content {
+                self.save(&config)?;
+                return Ok(value);
+            }
+            eprintln!(
+                "{} was changed while updating it, trying again",
+                self.path().display()
+            );
+        }
+
+        Err(Error::ConfigModified(self.path().clone()).into())
+    }
+
+    /// Write the config without taking the config lock, see `update`
+    pub fn save(&self, config: &Config) -> Result<()> {
+        match self {
+            ConfigStore::Plain(path) => config.save(path),
+            ConfigStore::Vault { path, key } => config.save_encrypted(path, key),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, ConfigStore::Vault { .. })
+    }
+
+    /// The file the config is stored in
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            ConfigStore::Plain(path) => path,
+            ConfigStore::Vault { path, .. } => path,
+        }
+    }
+}
+
+/// Set `key` of a TOML table to `new`, keeping the formatting of the keys and
+/// values that are already there and equal. `written` is the item as the config
+/// wrote it before; only keys in there are removed when `new` lacks them.
+fn update_item(table: &mut dyn TableLike, key: &str, new: &Item, written: Option<&Item>) {
+    match table.get_mut(key) {
+        Some(old) => merge_item(old, new, written),
+        None => {
+            // Inline tables turn a new table into an inline one on their own
+            table.insert(key, detach(new));
+        }
+    }
+}
+
+fn merge_item(old: &mut Item, new: &Item, written: Option<&Item>) {
+    if let (Some(old_table), Some(new_table)) = (old.as_table_like_mut(), new.as_table_like()) {
+        merge_table(old_table, new_table, written.and_then(Item::as_table_like));
+        return;
+    }
+
+    match (old.as_value_mut(), new.as_value()) {
+        (Some(old_value), Some(new_value)) => {
+            if !same_value(old_value, new_value) {
+                // Keep the comment after the value
+                let decor = old_value.decor().clone();
+                *old_value = new_value.clone();
+                *old_value.decor_mut() = decor;
+            }
+        }
+        _ => *old = detach(new),
+    }
+}
+
+fn merge_table(old: &mut dyn TableLike, new: &dyn TableLike, written: Option<&dyn TableLike>) {
+    let removed: Vec<String> = old
+        .iter()
+        .filter(|(key, _)| {
+            !new.contains_key(key) && written.is_some_and(|written| written.contains_key(key))
+        })
+        .map(|(key, _)| key.to_string())
+        .collect();
+    for key in removed {
+        old.remove(&key);
+    }
+
+    for (key, item) in new.iter() {
+        let written_item = written.and_then(|written| written.get(key));
+        update_item(old, key, item, written_item);
+    }
+}
+
+/// Whether two values are equal, regardless of how they are written
+fn same_value(old: &Value, new: &Value) -> bool {
+    match (old, new) {
+        (Value::String(a), Value::String(b)) => a.value() == b.value(),
+        (Value::Integer(a), Value::Integer(b)) => a.value() == b.value(),
+        (Value::Float(a), Value::Float(b)) => a.value() == b.value(),
+        (Value::Boolean(a), Value::Boolean(b)) => a.value() == b.value(),
+        (Value::Datetime(a), Value::Datetime(b)) => a.value() == b.value(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_value(a, b))
+        }
+        _ => false,
+    }
+}
+
+/// Copy of an item without the positions its tables had in the document it
+/// was parsed from, so new tables are written after the existing ones
+fn detach(item: &Item) -> Item {
+    match item {
+        Item::Table(table) => {
+            let mut copy = Table::new();
+            copy.set_implicit(table.is_implicit());
+            for (key, item) in table.iter() {
+                copy.insert(key, detach(item));
+            }
+            Item::Table(copy)
+        }
+        other => other.clone(),
+    }
+}
+
+/// Move a plaintext config into a new vault encrypted with `password`.
+/// The plaintext file is only removed once the vault has been read back.
+pub fn migrate_to_vault(
+    config_path: &PathBuf,
+    vault_path: &PathBuf,
+    password: &str,
+) -> Result<ConfigStore> {
+    let _lock = lock::lock_config(config_path)?;
+
+    if vault_path.exists() {
+        return Err(Error::VaultExists(vault_path.clone()));
+    }
+
+    let config = Config::load(config_path)?;
+    let store = ConfigStore::Vault {
+        path: vault_path.clone(),
+        key: VaultKey::new(password)?,
+    };
+    store.save(&config)?;
+
+    let reopened = store.load()?;
+    if reopened.tokens.len() != config.tokens.len() {
+        let _ = fs::remove_file(vault_path);
+        return Err(Error::InvalidVault(
+            "Vault verification failed, plaintext config was kept".to_string(),
+        ));
+    }
+
+    fs::remove_file(config_path)
+        .map_err(|e| Error::io("remove plaintext config file", config_path, e))?;
+    // The backups hold the same secrets in plaintext
+    backup::remove_backups(config_path)?;
+
+    Ok(store)
 }
 
 #[cfg(test)]
@@ -118,6 +783,110 @@ mod tests {
         assert_eq!(config.get_token("nonexistent"), None);
     }
 
+    #[test]
+    fn test_remove_token() {
+        let mut config = Config::default();
+        config.add_token("test".to_string(), "SECRET123".to_string());
+
+        assert!(config.remove_token("test").is_some());
+        assert!(config.remove_token("test").is_none());
+        assert!(config.list_token_names().is_empty());
+    }
+
+    #[test]
+    fn test_import_tokens() {
+        let token = |secret: &str| TokenData {
+            secret: secret.to_string(),
+            ..Default::default()
+        };
+        let mut config = Config::default();
+        config.add_token("Unknown".to_string(), "JBSWY3DPEHPK3PXP".to_string());
+        config.add_token("GitHub".to_string(), "GEZDGNBVGY3TQOJQ".to_string());
+
+        let report = config.import_tokens(
+            vec![
+                ("Unknown".to_string(), token("MFRGGZDFMZTWQ2LK")),
+                ("Unknown".to_string(), token("ONSWG4TFOQ======")),
+                // Same secret written differently
+                ("Work".to_string(), token("gezd gnbv gy3t qojq")),
+                ("GitLab".to_string(), token("NBSWY3DP")),
+            ],
+            ImportPolicy::Rename,
+        );
+        let outcomes: Vec<(&str, &ImportOutcome)> = report
+            .tokens
+            .iter()
+            .map(|token| (token.name.as_str(), &token.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                (
+                    "Unknown (2)",
+                    &ImportOutcome::Renamed {
+                        from: "Unknown".to_string()
+                    }
+                ),
+                (
+                    "Unknown (3)",
+                    &ImportOutcome::Renamed {
+                        from: "Unknown".to_string()
+                    }
+                ),
+                (
+                    "Work",
+                    &ImportOutcome::Duplicate {
+                        existing: "GitHub".to_string()
+                    }
+                ),
+                ("GitLab", &ImportOutcome::Added),
+            ]
+        );
+        assert_eq!(report.written(), 3);
+        assert_eq!(config.get_token("Unknown").unwrap(), "JBSWY3DPEHPK3PXP");
+        assert!(config.get_token_data("Work").is_none());
+        assert_eq!(
+            report.tokens[2].to_string(),
+            "Work: skipped, already in the config as \"GitHub\""
+        );
+
+        let skipped =
+            config.import_token("GitHub".to_string(), token("MZXW6YTB"), ImportPolicy::Skip);
+        assert_eq!(skipped.outcome, ImportOutcome::Skipped);
+        assert_eq!(config.get_token("GitHub").unwrap(), "GEZDGNBVGY3TQOJQ");
+
+        let overwritten = config.import_token(
+            "GitHub".to_string(),
+            token("MZXW6YTB"),
+            ImportPolicy::Overwrite,
+        );
+        assert_eq!(overwritten.outcome, ImportOutcome::Overwritten);
+        assert_eq!(config.get_token("GitHub").unwrap(), "MZXW6YTB");
+
+        // The same secret as an HOTP token is a different token
+        let hotp = TokenData {
+            token_type: TokenType::Hotp,
+            ..token("MZXW6YTB")
+        };
+        let added = config.import_token("Bank".to_string(), hotp, ImportPolicy::Rename);
+        assert_eq!(added.outcome, ImportOutcome::Added);
+    }
+
+    #[test]
+    fn test_import_policy_setting() {
+        let settings: Settings = toml::from_str(r#"import_policy = "skip""#).unwrap();
+        assert_eq!(settings.import_policy, ImportPolicy::Skip);
+        assert_eq!(Settings::default().import_policy, ImportPolicy::Rename);
+        assert_eq!(
+            "Overwrite".parse::<ImportPolicy>().unwrap(),
+            ImportPolicy::Overwrite
+        );
+        assert!(matches!(
+            "merge".parse::<ImportPolicy>(),
+            Err(Error::InvalidParameter(_))
+        ));
+    }
+
     #[test]
     fn test_list_token_names_alphabetical() {
         let mut config = Config::default();
@@ -139,6 +908,7 @@ mod tests {
             TokenData {
                 secret: "SECRET2".to_string(),
                 priority: Some(3),
+                ..Default::default()
             },
         );
         config.tokens.insert(
@@ -146,6 +916,7 @@ mod tests {
             TokenData {
                 secret: "SECRET1".to_string(),
                 priority: Some(1),
+                ..Default::default()
             },
         );
 
@@ -160,11 +931,11 @@ mod tests {
 
     #[test]
     fn test_save_and_load() {
-        let temp_dir = std::env::temp_dir();
-        let config_path = temp_dir.join("test_config.toml");
-
-        // Clean up if file exists
-        let _ = fs::remove_file(&config_path);
+        // A folder of its own, saving creates backups next to the config
+        let temp_dir = std::env::temp_dir().join("otp_bar_test_save_and_load");
+        let _ = fs::remove_dir_all(&temp_dir);
+        fs::create_dir_all(&temp_dir).unwrap();
+        let config_path = temp_dir.join("config.toml");
 
         // Create and save config
         let mut config = Config::default();
@@ -174,6 +945,7 @@ mod tests {
             TokenData {
                 secret: "HXDMVJECJJWSRB3H".to_string(),
                 priority: Some(1),
+                ..Default::default()
             },
         );
 
@@ -195,8 +967,143 @@ mod tests {
             Some(1)
         );
 
-        // Clean up
-        let _ = fs::remove_file(&config_path);
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_save_keeps_comments_and_order() {
+        let content = r#"# My tokens
+[settings]
+near_expiry = "wait" # explicit default
+
+# Work
+[tokens.vpn]
+secret = 'GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ'
+type = "hotp"
+counter = 4 # next code
+
+[tokens.old]
+secret = "JBSWY3DPEHPK3PXP"
+
+# Personal
+[tokens.github]
+secret = "HXDMVJECJJWSRB3H"
+"#;
+        let mut config = Config::parse(content).unwrap();
+        config.advance_counter("vpn").unwrap();
+        config.remove_token("old");
+        config.add_token("new".to_string(), "JBSWY3DPEHPK3PXP".to_string());
+
+        let saved = config.update_document(content).unwrap();
+        assert_eq!(Config::parse(&saved).unwrap(), config);
+        assert_eq!(
+            saved,
+            r#"# My tokens
+[settings]
+near_expiry = "wait" # explicit default
+
+# Work
+[tokens.vpn]
+secret = 'GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ'
+type = "hotp"
+counter = 5 # next code
+
+# Personal
+[tokens.github]
+secret = "HXDMVJECJJWSRB3H"
+
+[tokens.new]
+secret = "JBSWY3DPEHPK3PXP"
+"#
+        );
+
+        // Saving an unchanged config leaves the file as it is
+        assert_eq!(config.update_document(&saved).unwrap(), saved);
+
+        // A file that is not a valid config is not overwritten
+        assert!(matches!(
+            config.update_document("[tokens"),
+            Err(Error::ConfigParse(_))
+        ));
+    }
+
+    #[test]
+    fn test_save_keeps_keys_the_config_does_not_know() {
+        let content = r#"[settings]
+clipboard_clear_seconds = 20
+theme = "dark"
+
+[tokens.vpn]
+secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+issuer = "ACME"
+type = "hotp"
+counter = 4
+note = "keep me"
+"#;
+        let mut config = Config::parse(content).unwrap();
+        config.advance_counter("vpn").unwrap();
+        config.settings.clipboard_clear_seconds = None;
+        config.tokens.get_mut("vpn").unwrap().issuer = None;
+
+        // Keys of the config that became unset are removed, unknown ones stay
+        assert_eq!(
+            config.update_document(content).unwrap(),
+            r#"[settings]
+theme = "dark"
+
+[tokens.vpn]
+secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+type = "hotp"
+counter = 5
+note = "keep me"
+"#
+        );
+    }
+
+    #[test]
+    fn test_store_update_applies_change_again_after_external_edit() {
+        let temp_dir = std::env::temp_dir().join("otp_bar_test_store_update");
+        let _ = fs::remove_dir_all(&temp_dir);
+        fs::create_dir_all(&temp_dir).unwrap();
+        let config_path = temp_dir.join("config.toml");
+        fs::write(
+            &config_path,
+            "[tokens.github]\nsecret = \"JBSWY3DPEHPK3PXP\"\n",
+        )
+        .unwrap();
+        let store = ConfigStore::Plain(config_path.clone());
+
+        let mut attempts = 0;
+        store
+            .update(|config| {
+                attempts += 1;
+                if attempts == 1 {
+                    // An editor saves while the update is in progress
+                    fs::write(
+                        &config_path,
+                        "[tokens.github]\nsecret = \"JBSWY3DPEHPK3PXP\"\n\n[tokens.aws]\nsecret = \"MFRGGZDFMZTWQ2LK\"\n",
+                    )
+                    .unwrap();
+                }
+                config.add_token("gitlab".to_string(), "HXDMVJECJJWSRB3H".to_string());
+                Ok::<_, Error>(())
+            })
+            .unwrap();
+        assert_eq!(attempts, 2);
+
+        let config = store.load().unwrap();
+        assert_eq!(config.tokens.len(), 3);
+        assert!(config.get_token("aws").is_some());
+
+        // A failed update leaves the file alone
+        let result = store.update(|config| {
+            config.remove_token("github");
+            Err::<(), _>(Error::TokenNotFound("missing".to_string()))
+        });
+        assert!(matches!(result, Err(Error::TokenNotFound(_))));
+        assert!(store.load().unwrap().get_token("github").is_some());
+
+        let _ = fs::remove_dir_all(&temp_dir);
     }
 
     #[test]
@@ -210,4 +1117,249 @@ mod tests {
         let config = Config::load(&config_path).expect("Should return default config");
         assert_eq!(config.tokens.len(), 0);
     }
+
+    #[test]
+    fn test_settings() {
+        let config: Config = toml::from_str(
+            r#"
+[settings]
+clipboard_clear_seconds = 20
+
+[tokens.github]
+secret = "JBSWY3DPEHPK3PXP"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.settings.clipboard_clear_delay(),
+            Some(Duration::from_secs(20))
+        );
+
+        // Absent or zero keeps the code in the clipboard
+        assert_eq!(Settings::default().clipboard_clear_delay(), None);
+        let zero = Settings {
+            clipboard_clear_seconds: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(zero.clipboard_clear_delay(), None);
+
+        assert_eq!(config.settings.near_expiry_action(1), None);
+
+        let settings: Settings = toml::from_str(
+            r#"
+expiry_threshold_seconds = 3
+near_expiry = "next"
+"#,
+        )
+        .unwrap();
+        assert_eq!(settings.near_expiry_action(3), Some(NearExpiry::Next));
+        assert_eq!(settings.near_expiry_action(4), None);
+
+        let settings = Settings {
+            expiry_threshold_seconds: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(settings.near_expiry_action(2), Some(NearExpiry::Wait));
+
+        let settings = Settings {
+            editor_command: Some(vec![
+                "code".to_string(),
+                "--goto".to_string(),
+                "{file}:{line}:{column}".to_string(),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.editor_command_at("/tmp/config.toml", 7, 9),
+            Some(vec![
+                "code".to_string(),
+                "--goto".to_string(),
+                "/tmp/config.toml:7:9".to_string()
+            ])
+        );
+        assert_eq!(
+            Settings::default().editor_command_at("config.toml", 1, 1),
+            None
+        );
+
+        // An empty section is not written back
+        let content = toml::to_string(&Config::default()).unwrap();
+        assert!(!content.contains("[settings]"));
+    }
+
+    #[test]
+    fn test_token_type_defaults_to_totp() {
+        let config: Config = toml::from_str(
+            r#"
+            [tokens.github]
+            secret = "JBSWY3DPEHPK3PXP"
+            "#,
+        )
+        .unwrap();
+
+        let token = config.get_token_data("github").unwrap();
+        assert_eq!(token.token_type, TokenType::Totp);
+        assert_eq!(token.counter, None);
+    }
+
+    #[test]
+    fn test_hotp_token_round_trip() {
+        let config: Config = toml::from_str(
+            r#"
+            [tokens.vpn]
+            secret = "JBSWY3DPEHPK3PXP"
+            type = "hotp"
+            counter = 7
+            "#,
+        )
+        .unwrap();
+
+        let token = config.get_token_data("vpn").unwrap();
+        assert_eq!(token.token_type, TokenType::Hotp);
+        assert_eq!(token.counter, Some(7));
+
+        let serialized = toml::to_string_pretty(&config).unwrap();
+        assert!(serialized.contains("type = \"hotp\""));
+        assert!(serialized.contains("counter = 7"));
+    }
+
+    #[test]
+    fn test_advance_counter() {
+        let mut config = Config::default();
+        config.tokens.insert(
+            "vpn".to_string(),
+            TokenData {
+                secret: "JBSWY3DPEHPK3PXP".to_string(),
+                token_type: TokenType::Hotp,
+                ..Default::default()
+            },
+        );
+        config.add_token("github".to_string(), "HXDMVJECJJWSRB3H".to_string());
+
+        assert_eq!(config.advance_counter("vpn").unwrap(), 0);
+        assert_eq!(config.advance_counter("vpn").unwrap(), 1);
+        assert_eq!(config.get_token_data("vpn").unwrap().counter, Some(2));
+
+        assert!(matches!(
+            config.advance_counter("github"),
+            Err(Error::InvalidToken { .. })
+        ));
+        assert!(matches!(
+            config.advance_counter("nonexistent"),
+            Err(Error::TokenNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_otp_parameters() {
+        let config: Config = toml::from_str(
+            r#"
+            [tokens.default]
+            secret = "JBSWY3DPEHPK3PXP"
+
+            [tokens.custom]
+            secret = "JBSWY3DPEHPK3PXP"
+            algorithm = "SHA256"
+            digits = 8
+            period = 60
+            "#,
+        )
+        .unwrap();
+
+        let default = config.get_token_data("default").unwrap();
+        assert_eq!(default.algorithm(), Algorithm::Sha1);
+        assert_eq!(default.digits(), 6);
+        assert_eq!(default.period(), 30);
+
+        let custom = config.get_token_data("custom").unwrap();
+        assert_eq!(custom.algorithm(), Algorithm::Sha256);
+        assert_eq!(custom.digits(), 8);
+        assert_eq!(custom.period(), 60);
+    }
+
+    #[test]
+    fn test_migrate_to_vault() {
+        let temp_dir = std::env::temp_dir().join("otp_bar_test_migrate_to_vault");
+        let _ = fs::remove_dir_all(&temp_dir);
+        fs::create_dir_all(&temp_dir).unwrap();
+        let config_path = temp_dir.join("config.toml");
+        let vault_path = temp_dir.join("config.vault");
+
+        let mut config = Config::default();
+        config.add_token("github".to_string(), "JBSWY3DPEHPK3PXP".to_string());
+        config.save(&config_path).unwrap();
+        config.add_token("gitlab".to_string(), "HXDMVJECJJWSRB3H".to_string());
+        config.save(&config_path).unwrap();
+        assert_eq!(backup::list_backups(&config_path).unwrap().len(), 1);
+
+        let store = migrate_to_vault(&config_path, &vault_path, "correct horse").unwrap();
+        assert!(store.is_encrypted());
+        assert!(!config_path.exists());
+        assert!(backup::list_backups(&config_path).unwrap().is_empty());
+
+        let content = fs::read_to_string(&vault_path).unwrap();
+        assert!(!content.contains("JBSWY3DPEHPK3PXP"));
+
+        // Saving through the store keeps the vault readable with the password
+        let mut unlocked = store.load().unwrap();
+        unlocked.add_token("aws".to_string(), "MFRGGZDFMZTWQ2LK".to_string());
+        store.save(&unlocked).unwrap();
+
+        let (reopened, _) = Config::load_encrypted(&vault_path, "correct horse").unwrap();
+        assert_eq!(
+            reopened.get_token("github"),
+            Some(&"JBSWY3DPEHPK3PXP".to_string())
+        );
+        assert_eq!(
+            reopened.get_token("aws"),
+            Some(&"MFRGGZDFMZTWQ2LK".to_string())
+        );
+        assert!(Config::load_encrypted(&vault_path, "wrong").is_err());
+
+        // A second migration must not overwrite the existing vault
+        config.save(&config_path).unwrap();
+        assert!(migrate_to_vault(&config_path, &vault_path, "other").is_err());
+
+        let _ = fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_external_secret_sources() {
+        let config: Config = toml::from_str(
+            r#"
+            [tokens.github]
+            secret_command = ["pass", "show", "totp/github"]
+
+            [tokens.aws]
+            secret_file = "~/.secrets/aws"
+
+            [tokens.ci]
+            secret_env = "CI_TOTP_SECRET"
+            "#,
+        )
+        .unwrap();
+
+        let github = config.get_token_data("github").unwrap();
+        assert_eq!(github.secret, "");
+        assert_eq!(
+            github.secret_command,
+            Some(vec![
+                "pass".to_string(),
+                "show".to_string(),
+                "totp/github".to_string()
+            ])
+        );
+        assert_eq!(
+            config.get_token_data("aws").unwrap().secret_file.as_deref(),
+            Some("~/.secrets/aws")
+        );
+        assert_eq!(
+            config.get_token_data("ci").unwrap().secret_env.as_deref(),
+            Some("CI_TOTP_SECRET")
+        );
+
+        // External sources are not copied into the file as plaintext secrets
+        let serialized = toml::to_string_pretty(&config).unwrap();
+        assert!(!serialized.contains("secret ="));
+    }
 }
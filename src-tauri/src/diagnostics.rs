@@ -0,0 +1,185 @@
+use crate::config::{Config, TokenData};
+use crate::otp::decode_secret;
+use crate::secret;
+use serde::Deserialize;
+use std::collections::HashMap;
+use toml::Spanned;
+
+/// A problem in the config, with where to fix it if known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// Token the problem belongs to, `None` for the file as a whole
+    pub token: Option<String>,
+    pub message: String,
+    /// 1-based line and column in the config file
+    pub location: Option<(usize, usize)>,
+}
+
+impl Diagnostic {
+    /// A problem without a place in the file, such as an unreadable config
+    pub fn general(message: &str) -> Self {
+        Diagnostic {
+            token: None,
+            message: message.lines().next().unwrap_or_default().to_string(),
+            location: None,
+        }
+    }
+
+    /// One line description for the menu
+    pub fn summary(&self) -> String {
+        let place = match (&self.token, self.location) {
+            (Some(token), Some((line, _))) => format!("{} (line {})", token, line),
+            (Some(token), None) => token.clone(),
+            (None, Some((line, column))) => format!("Line {}, column {}", line, column),
+            (None, None) => "Config".to_string(),
+        };
+        format!("{}: {}", place, self.message)
+    }
+}
+
+/// Only the token names, with their position in the file
+#[derive(Deserialize)]
+struct TokenKeys {
+    #[serde(default)]
+    tokens: HashMap<Spanned<String>, toml::Value>,
+}
+
+/// Find the problems in the text of a config: a parse error, or the tokens
+/// that cannot produce a code.
+pub fn diagnose(content: &str) -> Vec<Diagnostic> {
+    let config: Config = match toml::from_str(content) {
+        Ok(config) => config,
+        Err(e) => {
+            return vec![Diagnostic {
+                token: None,
+                message: e.message().lines().collect::<Vec<_>>().join(": "),
+                location: e.span().map(|span| line_column(content, span.start)),
+            }]
+        }
+    };
+
+    let key_offsets: HashMap<String, usize> = toml::from_str::<TokenKeys>(content)
+        .map(|keys| {
+            keys.tokens
+                .into_keys()
+                .map(|key| (key.get_ref().clone(), key.span().start))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    config
+        .list_token_names()
+        .into_iter()
+        .filter_map(|name| {
+            let message = validate_token(&config.tokens[&name]).err()?;
+            Some(Diagnostic {
+                location: key_offsets
+                    .get(&name)
+                    .map(|offset| line_column(content, *offset)),
+                token: Some(name),
+                message,
+            })
+        })
+        .collect()
+}
+
+/// Check a token for problems that show without running its secret command
+pub fn validate_token(token: &TokenData) -> Result<(), String> {
+    secret::check_sources(token)?;
+
+    if !secret::is_external(token) {
+        decode_secret(&secret::resolve_secret(token)?)?;
+    }
+    if !(1..=9).contains(&token.digits()) {
+        return Err(format!(
+            "digits must be between 1 and 9, not {}",
+            token.digits()
+        ));
+    }
+    if token.period() == 0 {
+        return Err("period must be greater than 0".to_string());
+    }
+
+    Ok(())
+}
+
+/// 1-based line and column of a byte offset
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .map_or(0, |text| text.chars().count())
+        + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnose_parse_error() {
+        let diagnostics = diagnose("[tokens.github]\nsecret = \n");
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].token, None);
+        assert_eq!(diagnostics[0].location, Some((2, 10)));
+        assert!(!diagnostics[0].message.contains('\n'));
+    }
+
+    #[test]
+    fn test_diagnose_tokens() {
+        let content = r#"[settings]
+clipboard_clear_seconds = 20
+
+[tokens.good]
+secret = "JBSWY3DPEHPK3PXP"
+
+[tokens.bad]
+secret = "NOT-BASE32!"
+
+[tokens."no secret"]
+issuer = "Example"
+
+[tokens.pass]
+secret_command = ["pass", "otp/pass"]
+digits = 12
+"#;
+        let diagnostics = diagnose(content);
+
+        let find = |token: &str| {
+            diagnostics
+                .iter()
+                .find(|d| d.token.as_deref() == Some(token))
+                .cloned()
+        };
+        assert!(find("good").is_none());
+        assert_eq!(find("bad").unwrap().location, Some((7, 9)));
+        assert_eq!(find("no secret").unwrap().location, Some((10, 9)));
+        assert!(find("no secret").unwrap().message.contains("No secret"));
+        assert!(find("pass").unwrap().message.contains("digits"));
+        assert_eq!(diagnostics.len(), 3);
+    }
+
+    #[test]
+    fn test_diagnose_valid_config() {
+        assert!(diagnose("").is_empty());
+        assert!(diagnose("[tokens.a]\nsecret = \"JBSW Y3DP EHPK 3PXP\"\n").is_empty());
+    }
+
+    #[test]
+    fn test_summary() {
+        let diagnostic = Diagnostic {
+            token: Some("bad".to_string()),
+            message: "Invalid base32 secret".to_string(),
+            location: Some((7, 9)),
+        };
+        assert_eq!(diagnostic.summary(), "bad (line 7): Invalid base32 secret");
+        assert_eq!(
+            Diagnostic::general("Failed to read\nmore").summary(),
+            "Config: Failed to read"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors of the config, vault, secret, OTP and QR code modules.
+/// `lib.rs` and the CLI show them to the user through `Display`.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Reading or writing a file failed
+    #[error("Failed to {action} {}: {source}", path.display())]
+    Io {
+        action: &'static str,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse TOML config: {0}")]
+    ConfigParse(#[from] toml::de::Error),
+
+    #[error("Failed to edit TOML config: {0}")]
+    ConfigEdit(#[from] toml_edit::TomlError),
+
+    #[error("Failed to serialize config to TOML: {0}")]
+    ConfigSerialize(#[from] toml::ser::Error),
+
+    #[error("Config is not valid UTF-8: {0}")]
+    ConfigEncoding(#[from] std::str::Utf8Error),
+
+    /// Another program kept changing the config while the app was saving it
+    #[error("{} was changed by another program while saving, try again", .0.display())]
+    ConfigModified(PathBuf),
+
+    #[error("Token '{0}' not found")]
+    TokenNotFound(String),
+
+    /// A token exists but cannot be used for what was asked
+    #[error("Token '{name}' {reason}")]
+    InvalidToken { name: String, reason: String },
+
+    #[error("Failed to decode base32 secret: {0}")]
+    InvalidSecret(#[from] data_encoding::DecodeError),
+
+    /// An algorithm, number of digits or period that cannot produce a code
+    #[error("{0}")]
+    InvalidParameter(String),
+
+    #[error("Failed to {action} image {}: {source}", path.display())]
+    Image {
+        action: &'static str,
+        path: PathBuf,
+        #[source]
+        source: image::ImageError,
+    },
+
+    #[error("Failed to read PDF {}: {source}", path.display())]
+    Pdf {
+        path: PathBuf,
+        #[source]
+        source: lopdf::Error,
+    },
+
+    #[error("No QR code found in image")]
+    NoQrCode,
+
+    #[error(
+        "No QR code found in the images of the PDF. QR codes drawn as vector graphics \
+         are not read, import a screenshot of them instead"
+    )]
+    NoQrCodeInPdf,
+
+    #[error("Failed to decode QR code: {0}")]
+    QrDecode(#[from] rqrr::DeQRError),
+
+    #[error("No tokens to export")]
+    NoTokensToExport,
+
+    #[error("Failed to encode QR code: {0}")]
+    QrEncode(#[from] qrcode::types::QrError),
+
+    #[error("Failed to parse URL: {0}")]
+    Url(#[from] url::ParseError),
+
+    #[error("Failed to decode base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+
+    #[error("Failed to decode name: {0}")]
+    InvalidLabel(#[source] std::string::FromUtf8Error),
+
+    /// A number in an `otpauth://` URL that does not parse, e.g. the digits
+    #[error("Invalid {field}: {value}")]
+    InvalidNumber {
+        field: &'static str,
+        value: String,
+        #[source]
+        source: std::num::ParseIntError,
+    },
+
+    /// A QR code or URL that does not describe OTP tokens
+    #[error("{0}")]
+    InvalidOtpData(String),
+
+    /// A token whose secret sources are missing or conflicting, or whose secret is empty
+    #[error("{0}")]
+    SecretSource(String),
+
+    #[error("Failed to run {program}: {source}")]
+    SecretCommand {
+        program: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A secret command that exited with an error, with the first line it printed to stderr
+    #[error("`{command}` failed ({status}){}", .stderr.as_deref().map(|line| format!(": {}", line)).unwrap_or_default())]
+    SecretCommandFailed {
+        command: String,
+        status: std::process::ExitStatus,
+        stderr: Option<String>,
+    },
+
+    #[error("`{command}` printed invalid UTF-8")]
+    SecretCommandOutput {
+        command: String,
+        #[source]
+        source: std::string::FromUtf8Error,
+    },
+
+    #[error("Failed to read environment variable {name}: {source}")]
+    SecretEnv {
+        name: String,
+        #[source]
+        source: std::env::VarError,
+    },
+
+    #[error("Failed to derive vault key: {0}")]
+    VaultKey(#[source] argon2::Error),
+
+    #[error("Invalid key derivation parameters in vault: {0}")]
+    VaultParams(#[source] argon2::Error),
+
+    #[error("Failed to encrypt vault")]
+    VaultEncrypt(#[source] chacha20poly1305::aead::Error),
+
+    #[error("Wrong password or corrupted vault")]
+    WrongPassword(#[source] chacha20poly1305::aead::Error),
+
+    #[error("Failed to parse vault file: {0}")]
+    VaultParse(#[source] toml::de::Error),
+
+    #[error("Vault already exists at {}", .0.display())]
+    VaultExists(PathBuf),
+
+    /// A vault in an unknown format, or one that no longer matches the key in memory
+    #[error("{0}")]
+    InvalidVault(String),
+
+    #[error("Failed to show password prompt ({source}), set {env} instead", env = crate::vault::PASSWORD_ENV)]
+    PasswordPrompt {
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to create config watcher: {0}")]
+    Watcher(#[source] notify_debouncer_mini::notify::Error),
+
+    #[error("Failed to watch {}: {source}", path.display())]
+    Watch {
+        path: PathBuf,
+        #[source]
+        source: notify_debouncer_mini::notify::Error,
+    },
+
+    #[error("Failed to get system time: {0}")]
+    Time(#[from] std::time::SystemTimeError),
+}
+
+impl Error {
+    pub fn io(action: &'static str, path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        Error::Io {
+            action,
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+/// The message shown to the user, for the app code that works with `String` errors
+impl From<Error> for String {
+    fn from(error: Error) -> Self {
+        error.to_string()
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
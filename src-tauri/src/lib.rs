@@ -1,24 +1,119 @@
+use notify_debouncer_mini::notify::RecommendedWatcher;
+use notify_debouncer_mini::Debouncer;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tauri::{
-    menu::{Menu, MenuBuilder, MenuItemBuilder, MenuItemKind, PredefinedMenuItem},
+    menu::{
+        CheckMenuItemBuilder, Menu, MenuBuilder, MenuItemBuilder, MenuItemKind, PredefinedMenuItem,
+        SubmenuBuilder,
+    },
     tray::TrayIconBuilder,
     ActivationPolicy, AppHandle, Manager, Wry,
 };
 use tauri_plugin_dialog::FilePath;
 use tauri_plugin_opener::OpenerExt;
 
+mod backup;
+pub mod cli;
 mod config;
+mod diagnostics;
+mod error;
+mod lock;
 mod otp;
+mod pdf;
+mod preprocess;
 mod qr;
+mod secret;
+mod vault;
+mod watcher;
 
-use config::Config;
-use otp::{generate_otp, get_otp_remaining_time, is_otp_in_warning_period};
+use config::{
+    Config, ConfigStore, ImportOutcome, ImportPolicy, ImportReport, NearExpiry, Settings,
+    TokenData, TokenType,
+};
+use diagnostics::Diagnostic;
+use otp::{
+    generate_hotp, generate_otp, generate_otp_at, get_current_step, get_otp_remaining_time,
+    is_otp_in_warning_period, DEFAULT_PERIOD,
+};
+
+/// Shown in place of the code for HOTP tokens until one is generated
+const HOTP_PLACEHOLDER: &str = "••••••";
+
+/// Menu id prefix of the token entries that copy a code, followed by the token id.
+/// Keeps token names apart from the ids of the other menu items.
+const TOKEN_PREFIX: &str = "token:";
+
+/// Menu id prefix of the "Show QR code" entries, followed by the token id
+const SHOW_QR_PREFIX: &str = "show_qr:";
+
+/// Menu id prefix of the countdown items, followed by the period in seconds
+const TIMER_PREFIX: &str = "timer:";
+
+/// Menu id prefix of the config problems, followed by their index
+const DIAGNOSTIC_PREFIX: &str = "diagnostic:";
+
+/// Menu id prefix of the "Restore backup" entries, followed by the file name of the backup
+const RESTORE_PREFIX: &str = "restore:";
+
+/// Menu id of the submenu listing the tokens of a pending import
+const PENDING_IMPORT_ID: &str = "pending_import";
+
+/// Menu id prefix of the tokens of a pending import, followed by their index
+const PENDING_TOKEN_PREFIX: &str = "pending_token:";
+
+/// Menu id prefix of the tokens in the "Export QR codes" submenu, followed by the token id
+const EXPORT_TOKEN_PREFIX: &str = "export_token:";
 
 struct MenuState(Mutex<Menu<Wry>>);
 
+/// Period and step of each TOTP code shown in the menu, by token id
+struct TotpItems(Mutex<HashMap<String, TotpItem>>);
+
+#[derive(Debug, Clone, Copy)]
+struct TotpItem {
+    period: u64,
+    /// Step the code in the menu was generated for
+    step: u64,
+}
+
+/// Where the config is read from, either the plaintext file or the unlocked vault
+struct StoreState(Mutex<ConfigStore>);
+
+/// Secrets resolved from commands, files or the environment, by token id.
+/// Failures are cached too, so a broken command is not re-run on every refresh.
+struct SecretCache(Mutex<HashMap<String, Result<String, String>>>);
+
+/// Tokens whose secret is being resolved in the background for the menu
+struct ResolvingSecrets(Mutex<HashSet<String>>);
+
+/// The config as loaded when the menu was last built. Menu building and the
+/// periodic refresh read from here instead of from disk.
+struct ConfigState(Mutex<Config>);
+
+/// Why the config could not be loaded, shown in the menu until it is fixed
+struct ConfigError(Mutex<Option<String>>);
+
+/// Problems found in the config when it was last loaded
+struct ConfigDiagnostics(Mutex<Vec<Diagnostic>>);
+
+/// Keeps the config file watch alive
+struct ConfigWatcher(Mutex<Option<Debouncer<RecommendedWatcher>>>);
+
+/// Incremented on every copy, so only the timer of the latest copy clears the clipboard
+struct ClipboardGeneration(AtomicU64);
+
+/// Tokens decoded from QR codes that wait for the user to choose which ones to import
+struct PendingImport(Mutex<Option<qr::ImportPlan>>);
+
+/// Tokens unchecked in the "Export QR codes" submenu. New tokens are exported
+/// unless they are unchecked too.
+struct ExportExclusions(Mutex<HashSet<String>>);
+
 fn get_config_dir() -> PathBuf {
     let home = dirs::home_dir().expect("Could not find home directory");
     let config_dir = home.join(".config/otp-bar");
@@ -33,83 +128,755 @@ fn get_config_file_path() -> PathBuf {
     get_config_dir().join("config.toml")
 }
 
-fn list_token_ids() -> Vec<String> {
-    let config_path = get_config_file_path();
-    Config::load(&config_path)
-        .map(|config| config.list_token_names())
-        .unwrap_or_default()
+fn get_vault_file_path() -> PathBuf {
+    get_config_dir().join("config.vault")
 }
 
-fn read_token(id: &str) -> Result<String, String> {
-    let config_path = get_config_file_path();
-    let config = Config::load(&config_path)?;
+/// Use the encrypted vault if there is one, asking for its password
+fn open_config_store() -> Result<ConfigStore, String> {
+    let vault_path = get_vault_file_path();
+    if !vault_path.exists() {
+        return Ok(ConfigStore::Plain(get_config_file_path()));
+    }
+
+    // A password from the environment gives the same result on every try, and
+    // each try runs the slow key derivation, so it is only tried once
+    if let Ok(password) = std::env::var(vault::PASSWORD_ENV) {
+        let (_, key) = Config::load_encrypted(&vault_path, &password)?;
+        return Ok(ConfigStore::Vault {
+            path: vault_path,
+            key,
+        });
+    }
+
+    let mut message = "Enter the password to unlock OTP Bar";
+    for _ in 0..3 {
+        let password = vault::prompt_password(message)?
+            .ok_or_else(|| "Unlocking the vault was cancelled".to_string())?;
+
+        match Config::load_encrypted(&vault_path, &password) {
+            Ok((_, key)) => {
+                return Ok(ConfigStore::Vault {
+                    path: vault_path,
+                    key,
+                })
+            }
+            Err(e) => eprintln!("Failed to unlock vault: {}", e),
+        }
+        message = "Wrong password, try again";
+    }
+
+    Err("Too many failed attempts to unlock the vault".to_string())
+}
+
+fn config_store(app: &AppHandle) -> ConfigStore {
+    let state = app.state::<StoreState>();
+    let store = state.0.lock().unwrap();
+    store.clone()
+}
+
+/// Re-read the config from disk into `ConfigState`. If it cannot be loaded,
+/// the last good config stays in use and the error is kept for the menu.
+fn load_config(app: &AppHandle) {
+    let (loaded, diagnostics) = match config_store(app).read_content().map_err(String::from) {
+        Ok(content) => (
+            Config::parse(&content).map_err(String::from),
+            diagnostics::diagnose(&content),
+        ),
+        Err(e) => {
+            let diagnostic = Diagnostic::general(&e);
+            (Err(e), vec![diagnostic])
+        }
+    };
+    *app.state::<ConfigDiagnostics>().0.lock().unwrap() = diagnostics;
 
-    config
-        .get_token(id)
+    let error = match loaded {
+        Ok(config) => {
+            *app.state::<ConfigState>().0.lock().unwrap() = config;
+            None
+        }
+        Err(e) => {
+            eprintln!("Failed to load config: {}", e);
+            Some(e)
+        }
+    };
+    *app.state::<ConfigError>().0.lock().unwrap() = error;
+}
+
+/// Reload the menu after the config changed on disk. Writes made by the app
+/// itself, such as advancing an HOTP counter, leave the loaded config equal
+/// to the one in memory and are skipped.
+fn handle_config_change(app: &AppHandle) {
+    let unchanged = match config_store(app).load().map_err(String::from) {
+        Ok(config) => {
+            app.state::<ConfigError>().0.lock().unwrap().is_none()
+                && *app.state::<ConfigState>().0.lock().unwrap() == config
+        }
+        Err(e) => app.state::<ConfigError>().0.lock().unwrap().as_ref() == Some(&e),
+    };
+
+    if !unchanged {
+        println!("Config changed on disk, reloading");
+        reload_menu(app);
+    }
+}
+
+/// Watch both the plaintext config and the vault, so the watch survives encrypting the config
+fn watch_config(app: &AppHandle) {
+    let handle = app.clone();
+    match watcher::watch_files(
+        &[get_config_file_path(), get_vault_file_path()],
+        move || handle_config_change(&handle),
+    ) {
+        Ok(debouncer) => *app.state::<ConfigWatcher>().0.lock().unwrap() = Some(debouncer),
+        Err(e) => eprintln!("Config changes will need \"Apply config\": {}", e),
+    }
+}
+
+fn read_settings(app: &AppHandle) -> Settings {
+    app.state::<ConfigState>()
+        .0
+        .lock()
+        .unwrap()
+        .settings
+        .clone()
+}
+
+fn list_token_ids(app: &AppHandle) -> Vec<String> {
+    app.state::<ConfigState>()
+        .0
+        .lock()
+        .unwrap()
+        .list_token_names()
+}
+
+fn generate_token_otp(token: &TokenData) -> Result<String, String> {
+    Ok(generate_otp(
+        &token.secret,
+        token.algorithm(),
+        token.digits(),
+        token.period(),
+    )?)
+}
+
+/// Fill in the secret of a token that refers to an external source. Runs the
+/// secret command if it is not cached, so keep it off the main thread.
+fn resolve_token_secret(
+    app: &AppHandle,
+    id: &str,
+    mut token: TokenData,
+) -> Result<TokenData, String> {
+    if !secret::is_external(&token) {
+        return Ok(token);
+    }
+
+    let cache = app.state::<SecretCache>();
+    let cached = cache.0.lock().unwrap().get(id).cloned();
+    let resolved = match cached {
+        Some(resolved) => resolved,
+        None => {
+            // The command may take a while (e.g. a GPG prompt), so do not hold the lock
+            let resolved = secret::resolve_secret(&token).map_err(String::from);
+            cache
+                .0
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), resolved.clone());
+            resolved
+        }
+    };
+
+    token.secret = resolved?;
+    Ok(token)
+}
+
+/// Drop a cached secret failure so the next use tries the source again
+fn forget_failed_secret(app: &AppHandle, id: &str) {
+    let cache = app.state::<SecretCache>();
+    let mut cache = cache.0.lock().unwrap();
+    if matches!(cache.get(id), Some(Err(_))) {
+        cache.remove(id);
+    }
+}
+
+fn loaded_token(app: &AppHandle, id: &str) -> Result<TokenData, String> {
+    app.state::<ConfigState>()
+        .0
+        .lock()
+        .unwrap()
+        .get_token_data(id)
         .cloned()
         .ok_or_else(|| format!("Token '{}' not found", id))
 }
 
-fn write_token(user_name: &str, token: &str) -> Result<(), String> {
-    let config_path = get_config_file_path();
-    let mut config = Config::load(&config_path).unwrap_or_else(|e| {
-        eprintln!("Warning: Failed to load config ({}), using default", e);
-        Config::default()
+/// A token with its secret, running its secret command if needed
+fn read_token(app: &AppHandle, id: &str) -> Result<TokenData, String> {
+    resolve_token_secret(app, id, loaded_token(app, id)?)
+}
+
+/// A token with its secret for the menu, without waiting for a secret command.
+/// Returns `None` while the secret is resolved in the background; the menu is
+/// rebuilt once it is known.
+fn menu_token(app: &AppHandle, id: &str) -> Option<Result<TokenData, String>> {
+    let mut token = match loaded_token(app, id) {
+        Ok(token) => token,
+        Err(e) => return Some(Err(e)),
+    };
+    if !secret::is_external(&token) {
+        return Some(Ok(token));
+    }
+
+    let cached = app
+        .state::<SecretCache>()
+        .0
+        .lock()
+        .unwrap()
+        .get(id)
+        .cloned();
+    match cached {
+        Some(resolved) => Some(resolved.map(|secret| {
+            token.secret = secret;
+            token
+        })),
+        None => {
+            resolve_in_background(app, id, token);
+            None
+        }
+    }
+}
+
+/// Resolve the secret of a token on a background thread, then rebuild the
+/// menu once every secret being resolved is known
+fn resolve_in_background(app: &AppHandle, id: &str, token: TokenData) {
+    let started = app
+        .state::<ResolvingSecrets>()
+        .0
+        .lock()
+        .unwrap()
+        .insert(id.to_string());
+    if !started {
+        return;
+    }
+
+    let app = app.clone();
+    let id = id.to_string();
+    tauri::async_runtime::spawn_blocking(move || {
+        // The result is kept in the cache, failures are shown by the menu
+        let _ = resolve_token_secret(&app, &id, token);
+
+        let done = {
+            let resolving = app.state::<ResolvingSecrets>();
+            let mut resolving = resolving.0.lock().unwrap();
+            resolving.remove(&id);
+            resolving.is_empty()
+        };
+        if done {
+            rebuild_menu(&app);
+        }
     });
+}
+
+/// Generate the next HOTP code of a token. The advanced counter is saved
+/// before the code is handed out, so a code is never issued twice.
+fn next_hotp_code(app: &AppHandle, id: &str) -> Result<String, String> {
+    // A secret command may wait for the user, so it runs before the config is locked
+    let resolved = read_token(app, id)?;
+
+    // The code is only handed out once its counter has been saved
+    let (otp, token) = config_store(app).update(|config| {
+        let counter = config.advance_counter(id)?;
+        let mut token = config
+            .get_token_data(id)
+            .cloned()
+            .ok_or_else(|| format!("Token '{}' not found", id))?;
+        if secret::is_external(&token) {
+            token.secret = resolved.secret.clone();
+        }
+        let otp = generate_hotp(&token.secret, token.algorithm(), token.digits(), counter)?;
+        Ok::<_, String>((otp, token))
+    })?;
 
-    config.add_token(user_name.to_string(), token.to_string());
-    config.save(&config_path)?;
+    // Keep the in-memory counter in step without picking up other unapplied edits
+    let state = app.state::<ConfigState>();
+    if let Some(current) = state.0.lock().unwrap().tokens.get_mut(id) {
+        current.counter = token.counter;
+    }
+
+    Ok(otp)
+}
+
+/// Add imported tokens to the config, handling taken names according to `policy`
+fn merge_tokens(
+    config: &mut Config,
+    tokens: &[qr::TokenData],
+    policy: ImportPolicy,
+) -> ImportReport {
+    config.import_tokens(
+        tokens
+            .iter()
+            .map(|token| (token.display_name(), token.to_config_token())),
+        policy,
+    )
+}
+
+/// Add imported tokens to the config in a single load and save
+fn write_tokens(app: &AppHandle, tokens: &[qr::TokenData]) -> Result<ImportReport, String> {
+    // All tokens are added in one transaction. A config that cannot be read
+    // is never replaced, the user may be editing it.
+    config_store(app).update(|config| {
+        let policy = config.settings.import_policy;
+        Ok::<_, String>(merge_tokens(config, tokens, policy))
+    })
+}
+
+/// Open the config for editing, at `location` if the editor command supports it
+fn open_config_at(app: &AppHandle, location: Option<(usize, usize)>) -> Result<(), String> {
+    let config_path = get_config_file_path().to_string_lossy().to_string();
+    let (line, column) = location.unwrap_or((1, 1));
+
+    match read_settings(app).editor_command_at(&config_path, line, column) {
+        Some(command) => {
+            let mut child = std::process::Command::new(&command[0])
+                .args(&command[1..])
+                .spawn()
+                .map_err(|e| format!("Failed to run {}: {}", command[0], e))?;
+            // Reap the editor when it exits
+            std::thread::spawn(move || child.wait());
+        }
+        None => app
+            .opener()
+            .open_path(config_path, None::<&str>)
+            .map_err(|e| e.to_string())?,
+    }
 
     Ok(())
 }
 
-fn get_timer_display_text() -> String {
-    let remaining_time = get_otp_remaining_time();
-    if is_otp_in_warning_period() {
-        format!("⚠️ Time: {}s", remaining_time)
+/// Countdown of the codes with `period`. The period is only named when
+/// tokens with different periods are shown.
+fn get_timer_display_text(settings: &Settings, period: u64, show_period: bool) -> String {
+    // Only the periods of tracked codes are shown, which are never 0
+    let remaining_time = get_otp_remaining_time(period).unwrap_or_default();
+    let label = if show_period {
+        format!("Time ({}s)", period)
+    } else {
+        "Time".to_string()
+    };
+
+    if let Some(action) = settings.near_expiry_action(remaining_time) {
+        match action {
+            NearExpiry::Wait => format!(
+                "⏳ {}: {}s (copy waits for next code)",
+                label, remaining_time
+            ),
+            NearExpiry::Next => format!("⏭️ {}: {}s (copy gives next code)", label, remaining_time),
+        }
+    } else if is_otp_in_warning_period(remaining_time) {
+        format!("⚠️ {}: {}s", label, remaining_time)
     } else {
-        format!("⏱️ Time: {}s", remaining_time)
+        format!("⏱️ {}: {}s", label, remaining_time)
+    }
+}
+
+/// Distinct periods of the TOTP codes in the menu, the default if there are none
+fn timer_periods(items: &HashMap<String, TotpItem>) -> Vec<u64> {
+    let mut periods: Vec<u64> = items.values().map(|item| item.period).collect();
+    periods.sort_unstable();
+    periods.dedup();
+    if periods.is_empty() {
+        periods.push(DEFAULT_PERIOD);
+    }
+    periods
+}
+
+fn into_path(file_path: FilePath) -> Result<PathBuf, String> {
+    match file_path {
+        FilePath::Path(p) => Ok(p),
+        _ => Err("Only file paths are supported".to_string()),
     }
 }
 
 async fn handle_configure(app: AppHandle) -> Result<(), String> {
     use tauri_plugin_dialog::DialogExt;
 
-    if let Some(file_path) = app
+    if let Some(file_paths) = app
         .dialog()
         .file()
-        .add_filter("Images", &["png", "jpg", "jpeg"])
-        .blocking_pick_file()
+        .add_filter("Images and PDFs", qr::IMPORT_EXTENSIONS)
+        .blocking_pick_files()
+    {
+        let paths = file_paths
+            .into_iter()
+            .map(into_path)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        import_qr_images(&app, &paths)?;
+    }
+
+    Ok(())
+}
+
+async fn handle_configure_folder(app: AppHandle) -> Result<(), String> {
+    use tauri_plugin_dialog::DialogExt;
+
+    if let Some(folder) = app.dialog().file().blocking_pick_folder() {
+        let paths = qr::image_files_in_dir(&into_path(folder)?)?;
+        if paths.is_empty() {
+            return Err("No images found in the selected folder".to_string());
+        }
+
+        import_qr_images(&app, &paths)?;
+    }
+
+    Ok(())
+}
+
+/// Decode the tokens in the images and list them in the "Pending import" menu,
+/// where the user picks the ones to import
+fn import_qr_images(app: &AppHandle, paths: &[PathBuf]) -> Result<(), String> {
+    use tauri_plugin_dialog::{DialogExt, MessageDialogKind};
+
+    let import = qr::parse_qr_images(paths)?;
+    let plan = {
+        let config = app.state::<ConfigState>();
+        let config = config.0.lock().unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_secs();
+        qr::plan_import(import, &config, config.settings.import_policy, now)
+    };
+
+    let mut message = format!(
+        "Found {} token(s). Choose the ones to import in the \"Pending import\" menu.",
+        plan.tokens.len()
+    );
+    if !plan.warnings.is_empty() {
+        message = format!("{}\n\n{}", message, plan.warnings.join("\n"));
+    }
+
+    *app.state::<PendingImport>().0.lock().unwrap() = Some(plan);
+    rebuild_menu(app);
+
+    app.dialog()
+        .message(message)
+        .title("QR code import")
+        .kind(MessageDialogKind::Info)
+        .blocking_show();
+
+    Ok(())
+}
+
+/// Write the selected tokens of the pending import to the config
+async fn handle_import_selected(app: AppHandle) -> Result<(), String> {
+    use tauri_plugin_dialog::{DialogExt, MessageDialogKind};
+
+    let Some(plan) = app.state::<PendingImport>().0.lock().unwrap().clone() else {
+        return Ok(());
+    };
+    let report = write_tokens(&app, &plan.selected_tokens())?;
+    *app.state::<PendingImport>().0.lock().unwrap() = None;
+
+    // Restart the application
+    reload_menu(&app);
+
+    app.dialog()
+        .message(report.to_string())
+        .title("QR code import")
+        .kind(MessageDialogKind::Info)
+        .blocking_show();
+
+    Ok(())
+}
+
+/// Select or deselect a token of the pending import
+fn toggle_pending_token(app: &AppHandle, index: usize) {
+    if let Some(planned) = app
+        .state::<PendingImport>()
+        .0
+        .lock()
+        .unwrap()
+        .as_mut()
+        .and_then(|plan| plan.tokens.get_mut(index))
+        .filter(|planned| planned.can_import())
     {
-        let file_path_str = match file_path {
-            FilePath::Path(p) => p.to_string_lossy().to_string(),
-            _ => return Err("Only file paths are supported".to_string()),
+        planned.selected = !planned.selected;
+    }
+    rebuild_menu(app);
+}
+
+/// "287082: GitLab (alice) · GitLab · TOTP", noting when the token would be
+/// renamed or left out
+fn get_pending_token_text(planned: &qr::PlannedToken) -> String {
+    let code = match &planned.code {
+        Ok(code) => code.clone(),
+        Err(_) => "⚠️".to_string(),
+    };
+    let mut details = vec![planned.token.display_name()];
+    details.extend(planned.token.issuer.clone());
+    details.push(
+        match planned.token.token_type {
+            TokenType::Totp => "TOTP",
+            TokenType::Hotp => "HOTP",
+        }
+        .to_string(),
+    );
+
+    let note = match &planned.import.outcome {
+        ImportOutcome::Added => None,
+        ImportOutcome::Renamed { .. } => Some(format!("added as {}", planned.import.name)),
+        ImportOutcome::Overwritten => Some("replaces the existing token".to_string()),
+        ImportOutcome::Skipped => Some("name is taken".to_string()),
+        ImportOutcome::Duplicate { existing } => Some(format!("already saved as {}", existing)),
+    };
+
+    match note {
+        Some(note) => format!("{}: {} ({})", code, details.join(" · "), note),
+        None => format!("{}: {}", code, details.join(" · ")),
+    }
+}
+
+/// Export the tokens checked in the "Export QR codes" submenu
+async fn handle_export(app: AppHandle) -> Result<(), String> {
+    use tauri_plugin_dialog::{DialogExt, MessageDialogKind};
+
+    if let Some(folder) = app.dialog().file().blocking_pick_folder() {
+        let folder = into_path(folder)?;
+
+        let config = config_store(&app).load()?;
+        let names: Vec<String> = {
+            let excluded = app.state::<ExportExclusions>();
+            let excluded = excluded.0.lock().unwrap();
+            config
+                .list_token_names()
+                .into_iter()
+                .filter(|name| !excluded.contains(name))
+                .collect()
         };
-        let tokens = qr::parse_qr_and_extract_tokens(&file_path_str)?;
 
-        for token_data in tokens {
-            write_token(&token_data.name, &token_data.secret)?;
+        let export = qr::export_config_tokens(&config, &names, &folder, |name, token| {
+            resolve_token_secret(&app, name, token.clone())
+        })?;
+
+        let mut message = format!(
+            "Exported {} token(s) to {} QR code(s) in {}.\nScan them with Google Authenticator in order.",
+            names.len() - export.skipped.len(),
+            export.files.len(),
+            folder.display()
+        );
+        if !export.skipped.is_empty() {
+            message.push_str(&format!("\n\nSkipped:\n{}", export.skipped.join("\n")));
+        }
+
+        app.dialog()
+            .message(message)
+            .title("Export QR codes")
+            .kind(MessageDialogKind::Info)
+            .blocking_show();
+    }
+
+    Ok(())
+}
+
+/// Check or uncheck a token in the "Export QR codes" submenu
+fn toggle_export_token(app: &AppHandle, id: &str) {
+    {
+        let excluded = app.state::<ExportExclusions>();
+        let mut excluded = excluded.0.lock().unwrap();
+        if !excluded.remove(id) {
+            excluded.insert(id.to_string());
         }
+    }
+    rebuild_menu(app);
+}
+
+/// Save a token as an `otpauth://` QR code image and open it, so it can be
+/// scanned when enrolling another device
+async fn handle_show_qr(app: AppHandle, id: String) -> Result<(), String> {
+    use tauri_plugin_dialog::DialogExt;
+
+    let token = qr::TokenData::from_config(&id, &read_token(&app, &id)?);
+
+    if let Some(file_path) = app
+        .dialog()
+        .file()
+        .add_filter("PNG image", &["png"])
+        .set_file_name(format!("{}.png", id))
+        .blocking_save_file()
+    {
+        let path = into_path(file_path)?;
+        qr::write_qr_png(&qr::build_otpauth_url(&token), &path)?;
 
-        // Restart the application
-        reload_menu(&app);
+        app.opener()
+            .open_path(path.to_string_lossy().to_string(), None::<&str>)
+            .map_err(|e| format!("Failed to open QR code image: {}", e))?;
     }
 
     Ok(())
 }
 
+/// Replace the config with one of its backups, asking for confirmation first
+async fn handle_restore_backup(app: AppHandle, name: String) -> Result<(), String> {
+    use tauri_plugin_dialog::{DialogExt, MessageDialogButtons, MessageDialogKind};
+
+    let store = config_store(&app);
+    let backup = backup::list_backups(store.path())?
+        .into_iter()
+        .find(|backup| backup.file_name() == name)
+        .ok_or_else(|| format!("Backup {} no longer exists", name))?;
+
+    let proceed = app
+        .dialog()
+        .message(format!(
+            "Replace the config with the backup from {}?\n\nThe current config is backed up first.",
+            backup.label
+        ))
+        .title("Restore backup")
+        .kind(MessageDialogKind::Warning)
+        .buttons(MessageDialogButtons::OkCancel)
+        .blocking_show();
+    if !proceed {
+        return Ok(());
+    }
+
+    {
+        let _lock = lock::lock_config(store.path())?;
+        backup::restore(store.path(), &backup.path)?;
+    }
+
+    reload_menu(&app);
+
+    Ok(())
+}
+
+/// Encrypt the plaintext config into a password protected vault
+async fn handle_encrypt_config(app: AppHandle) -> Result<(), String> {
+    let password = match vault::prompt_password("Choose a password to encrypt the config")? {
+        Some(password) if !password.is_empty() => password,
+        Some(_) => return Err("The password must not be empty".to_string()),
+        None => return Ok(()),
+    };
+    if vault::prompt_password("Enter the password again")?.as_deref() != Some(password.as_str()) {
+        return Err("The passwords do not match".to_string());
+    }
+
+    let store =
+        config::migrate_to_vault(&get_config_file_path(), &get_vault_file_path(), &password)?;
+    *app.state::<StoreState>().0.lock().unwrap() = store;
+
+    reload_menu(&app);
+
+    Ok(())
+}
+
 async fn copy_otp_to_clipboard(app: AppHandle, id: String) -> Result<(), String> {
-    let token = read_token(&id)?;
-    let otp = generate_otp(&token)?;
+    // Clicking a token whose secret could not be resolved tries again
+    forget_failed_secret(&app, &id);
+
+    // Secret commands and the HOTP counter update block, keep them off the async workers
+    let (token, hotp) = {
+        let (app, id) = (app.clone(), id.clone());
+        tauri::async_runtime::spawn_blocking(move || {
+            let token = read_token(&app, &id)?;
+            let hotp = match token.token_type {
+                TokenType::Totp => None,
+                TokenType::Hotp => Some(next_hotp_code(&app, &id)?),
+            };
+            Ok::<_, String>((token, hotp))
+        })
+        .await
+        .map_err(|e| format!("Failed to read token: {}", e))??
+    };
+    let otp = match hotp {
+        Some(otp) => otp,
+        None => totp_code_to_copy(&app, &token).await?,
+    };
 
     use tauri_plugin_clipboard_manager::ClipboardExt;
     app.clipboard()
-        .write_text(otp)
+        .write_text(otp.clone())
         .map_err(|e| format!("Failed to write to clipboard: {}", e))?;
 
+    let generation = app
+        .state::<ClipboardGeneration>()
+        .0
+        .fetch_add(1, Ordering::SeqCst)
+        + 1;
+    if let Some(delay) = read_settings(&app).clipboard_clear_delay() {
+        tauri::async_runtime::spawn(clear_clipboard_later(
+            app.clone(),
+            otp.clone(),
+            generation,
+            delay,
+        ));
+    }
+
+    // HOTP codes are only generated on demand, so show the one just issued
+    if token.token_type == TokenType::Hotp {
+        set_token_item_text(&app, &id, get_otp_text(&id, &otp));
+    }
+
     Ok(())
 }
 
+/// Replace the text of a token's menu item
+fn set_token_item_text(app: &AppHandle, id: &str, text: String) {
+    let menu_handle = {
+        let state = app.state::<MenuState>();
+        let menu = state.0.lock().unwrap();
+        menu.clone()
+    };
+    if let Some(MenuItemKind::MenuItem(item)) = menu_handle.get(&format!("{}{}", TOKEN_PREFIX, id))
+    {
+        let _ = item.set_text(text);
+    }
+}
+
+/// The TOTP code to copy, avoiding one that expires before it can be pasted
+async fn totp_code_to_copy(app: &AppHandle, token: &TokenData) -> Result<String, String> {
+    let remaining = get_otp_remaining_time(token.period())
+        .ok_or_else(|| "period must be greater than 0".to_string())?;
+
+    match read_settings(app).near_expiry_action(remaining) {
+        None => generate_token_otp(token),
+        Some(NearExpiry::Wait) => {
+            // Sleeping the whole seconds left always lands in the next period
+            tokio::time::sleep(Duration::from_secs(remaining)).await;
+            generate_token_otp(token)
+        }
+        Some(NearExpiry::Next) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|e| format!("Failed to get system time: {}", e))?
+                .as_secs();
+            Ok(generate_otp_at(
+                &token.secret,
+                token.algorithm(),
+                token.digits(),
+                token.period(),
+                now + token.period(),
+            )?)
+        }
+    }
+}
+
+/// Clear the clipboard after `delay`, unless something else was copied in the meantime
+async fn clear_clipboard_later(app: AppHandle, otp: String, generation: u64, delay: Duration) {
+    tokio::time::sleep(delay).await;
+
+    if app.state::<ClipboardGeneration>().0.load(Ordering::SeqCst) != generation {
+        return;
+    }
+
+    use tauri_plugin_clipboard_manager::ClipboardExt;
+    let clipboard = app.clipboard();
+    if clipboard.read_text().ok().as_deref() == Some(otp.as_str()) {
+        if let Err(e) = clipboard.clear() {
+            eprintln!("Failed to clear clipboard: {}", e);
+        }
+    }
+}
+
 fn get_otp_text(id: &String, otp: &String) -> String {
     format!("{}: {}", otp, id)
 }
@@ -123,16 +890,121 @@ fn create_menu(app: &AppHandle, token_ids: &[String]) -> Result<Menu<tauri::Wry>
         .build(app)
         .map_err(|e| format!("Failed to create configure menu item: {}", e))?;
 
+    let configure_folder_item = MenuItemBuilder::new("Load QR codes from folder")
+        .id("configure_folder")
+        .build(app)
+        .map_err(|e| format!("Failed to create configure folder menu item: {}", e))?;
+
+    // Tokens to export, all checked until the user unchecks some
+    let excluded = app.state::<ExportExclusions>().0.lock().unwrap().clone();
+    let mut export_menu =
+        SubmenuBuilder::new(app, "Export QR codes").enabled(!token_ids.is_empty());
+    for id in token_ids {
+        let item = CheckMenuItemBuilder::new(id)
+            .id(format!("{}{}", EXPORT_TOKEN_PREFIX, id))
+            .checked(!excluded.contains(id))
+            .build(app)
+            .map_err(|e| format!("Failed to create export token menu item: {}", e))?;
+        export_menu = export_menu.item(&item);
+    }
+    let export_item = MenuItemBuilder::new("Export selected…")
+        .id("export")
+        .enabled(token_ids.iter().any(|id| !excluded.contains(id)))
+        .build(app)
+        .map_err(|e| format!("Failed to create export menu item: {}", e))?;
+    let export_menu = export_menu
+        .separator()
+        .item(&export_item)
+        .build()
+        .map_err(|e| format!("Failed to create export submenu: {}", e))?;
+
     let restart_item = MenuItemBuilder::new("Apply config")
         .id("reload")
         .build(app)
         .map_err(|e| format!("Failed to create restart menu item: {}", e))?;
 
+    // An encrypted config cannot be edited by hand, offer to encrypt a plaintext one instead
+    let encrypted = config_store(app).is_encrypted();
     let edit_config_item = MenuItemBuilder::new("Edit config")
         .id("edit_config")
+        .enabled(!encrypted)
         .build(app)
         .map_err(|e| format!("Failed to create edit config menu item: {}", e))?;
 
+    let encrypt_config_item = MenuItemBuilder::new("Encrypt config")
+        .id("encrypt_config")
+        .enabled(!encrypted)
+        .build(app)
+        .map_err(|e| format!("Failed to create encrypt config menu item: {}", e))?;
+
+    // Backups of the file the config is stored in, newest first
+    let backups = backup::list_backups(config_store(app).path()).unwrap_or_else(|e| {
+        eprintln!("Failed to list backups: {}", e);
+        Vec::new()
+    });
+    let mut restore_menu = SubmenuBuilder::new(app, "Restore backup").enabled(!backups.is_empty());
+    for backup in &backups {
+        let item = MenuItemBuilder::new(&backup.label)
+            .id(format!("{}{}", RESTORE_PREFIX, backup.file_name()))
+            .build(app)
+            .map_err(|e| format!("Failed to create restore backup menu item: {}", e))?;
+        restore_menu = restore_menu.item(&item);
+    }
+    let restore_menu = restore_menu
+        .build()
+        .map_err(|e| format!("Failed to create restore backup submenu: {}", e))?;
+
+    // Tokens of a pending import, selected ones are written by "Import selected"
+    let pending_import = app.state::<PendingImport>().0.lock().unwrap().clone();
+    let pending_menu = match &pending_import {
+        Some(plan) => {
+            let mut pending_menu = SubmenuBuilder::with_id(
+                app,
+                PENDING_IMPORT_ID,
+                format!(
+                    "Pending import ({} of {} selected)",
+                    plan.selected_count(),
+                    plan.tokens.len()
+                ),
+            );
+            for (index, planned) in plan.tokens.iter().enumerate() {
+                // Duplicates and taken names would not be written, so they cannot be checked
+                let item = CheckMenuItemBuilder::new(get_pending_token_text(planned))
+                    .id(format!("{}{}", PENDING_TOKEN_PREFIX, index))
+                    .checked(planned.selected)
+                    .enabled(planned.can_import())
+                    .build(app)
+                    .map_err(|e| format!("Failed to create pending token menu item: {}", e))?;
+                pending_menu = pending_menu.item(&item);
+            }
+            pending_menu = pending_menu.separator();
+            for warning in &plan.warnings {
+                let item = MenuItemBuilder::new(format!("⚠️ {}", warning))
+                    .enabled(false)
+                    .build(app)
+                    .map_err(|e| format!("Failed to create import warning menu item: {}", e))?;
+                pending_menu = pending_menu.item(&item);
+            }
+            let import_item = MenuItemBuilder::new("Import selected")
+                .id("import_selected")
+                .enabled(plan.selected_count() > 0)
+                .build(app)
+                .map_err(|e| format!("Failed to create import selected menu item: {}", e))?;
+            let cancel_item = MenuItemBuilder::new("Cancel import")
+                .id("cancel_import")
+                .build(app)
+                .map_err(|e| format!("Failed to create cancel import menu item: {}", e))?;
+            Some(
+                pending_menu
+                    .item(&import_item)
+                    .item(&cancel_item)
+                    .build()
+                    .map_err(|e| format!("Failed to create pending import submenu: {}", e))?,
+            )
+        }
+        None => None,
+    };
+
     // Quit item
     let quit_item = PredefinedMenuItem::quit(app, Some("Quit"))
         .map_err(|e| format!("Failed to create quit menu item: {}", e))?;
@@ -141,35 +1013,104 @@ fn create_menu(app: &AppHandle, token_ids: &[String]) -> Result<Menu<tauri::Wry>
     let separator = PredefinedMenuItem::separator(app)
         .map_err(|e| format!("Failed to create separator: {}", e))?;
 
-    // Timer item
-    let timer_text = get_timer_display_text();
-    let timer_item = MenuItemBuilder::new(timer_text)
-        .id("timer")
-        .enabled(false)
-        .build(app)
-        .map_err(|e| format!("Failed to create timer menu item: {}", e))?;
+    // Token items, remembering the step each TOTP code was generated for
+    let mut totp_items = HashMap::new();
+    let mut token_items = Vec::new();
+    for id in token_ids {
+        let text = match menu_token(app, id) {
+            None => format!("⏳ {}: reading secret…", id),
+            Some(Ok(token)) => {
+                let otp = match token.token_type {
+                    TokenType::Totp => {
+                        // A period of 0 has no steps to track, its error is shown instead
+                        let period = token.period();
+                        if let Some(step) = get_current_step(period) {
+                            totp_items.insert(id.clone(), TotpItem { period, step });
+                        }
+                        generate_token_otp(&token)
+                    }
+                    TokenType::Hotp => Ok(HOTP_PLACEHOLDER.to_string()),
+                };
+                match otp {
+                    Ok(otp) => get_otp_text(&id, &otp),
+                    Err(e) => format!("⚠️ {}: {}", id, e),
+                }
+            }
+            Some(Err(e)) => format!("⚠️ {}: {}", id, e),
+        };
+
+        let item = MenuItemBuilder::new(text)
+            .id(format!("{}{}", TOKEN_PREFIX, id))
+            .build(app)
+            .map_err(|e| format!("Failed to create menu item: {}", e))?;
+        token_items.push(item);
+    }
 
+    // One timer item per period
+    let settings = read_settings(app);
+    let periods = timer_periods(&totp_items);
+    let mut timer_items = Vec::new();
+    for period in &periods {
+        let timer_text = get_timer_display_text(&settings, *period, periods.len() > 1);
+        let timer_item = MenuItemBuilder::new(timer_text)
+            .id(format!("{}{}", TIMER_PREFIX, period))
+            .enabled(false)
+            .build(app)
+            .map_err(|e| format!("Failed to create timer menu item: {}", e))?;
+        timer_items.push(timer_item);
+    }
+    *app.state::<TotpItems>().0.lock().unwrap() = totp_items;
+
+    let mut menu = menu.item(&configure_item).item(&configure_folder_item);
+    if let Some(pending_menu) = &pending_menu {
+        menu = menu.item(pending_menu);
+    }
     let mut menu = menu
-        .item(&configure_item)
+        .item(&export_menu)
         .item(&edit_config_item)
+        .item(&encrypt_config_item)
+        .item(&restore_menu)
         .item(&restart_item)
         .item(&quit_item)
-        .item(&separator)
-        .item(&timer_item)
         .item(&separator);
+    for timer_item in &timer_items {
+        menu = menu.item(timer_item);
+    }
+    menu = menu.item(&separator);
 
-    // Add token items
-    for id in token_ids {
-        let token = read_token(id).unwrap_or_default();
-        let otp = generate_otp(&token).unwrap_or_else(|_| "ERROR".to_string());
-        let text = get_otp_text(&id, &otp);
+    // Config problems, each opening the config where it can be fixed.
+    // After a parse error the tokens below are from the last config that could be loaded.
+    let diagnostics = app.state::<ConfigDiagnostics>().0.lock().unwrap().clone();
+    if !diagnostics.is_empty() {
+        for (index, diagnostic) in diagnostics.iter().enumerate() {
+            let item = MenuItemBuilder::new(format!("⚠️ {}", diagnostic.summary()))
+                .id(format!("{}{}", DIAGNOSTIC_PREFIX, index))
+                .enabled(!encrypted)
+                .build(app)
+                .map_err(|e| format!("Failed to create diagnostic menu item: {}", e))?;
+            menu = menu.item(&item);
+        }
+        menu = menu.item(&separator);
+    }
 
-        let item = MenuItemBuilder::new(text)
-            .id(id)
-            .build(app)
-            .map_err(|e| format!("Failed to create menu item: {}", e))?;
+    for item in &token_items {
+        menu = menu.item(item);
+    }
 
-        menu = menu.item(&item);
+    if !token_ids.is_empty() {
+        let mut show_qr_menu = SubmenuBuilder::new(app, "Show QR code");
+        for id in token_ids {
+            let item = MenuItemBuilder::new(id)
+                .id(format!("{}{}", SHOW_QR_PREFIX, id))
+                .build(app)
+                .map_err(|e| format!("Failed to create show QR menu item: {}", e))?;
+            show_qr_menu = show_qr_menu.item(&item);
+        }
+        let show_qr_menu = show_qr_menu
+            .build()
+            .map_err(|e| format!("Failed to create show QR submenu: {}", e))?;
+
+        menu = menu.item(&separator).item(&show_qr_menu);
     }
 
     menu.build()
@@ -177,72 +1118,122 @@ fn create_menu(app: &AppHandle, token_ids: &[String]) -> Result<Menu<tauri::Wry>
 }
 
 async fn update_menu_periodically(app: AppHandle) {
-    let mut previous_remaining_time = get_otp_remaining_time();
-
     loop {
         tokio::time::sleep(Duration::from_millis(500)).await;
 
-        let current_remaining_time = get_otp_remaining_time();
-
         // Get current menu from state
         let menu_handle = {
             let state = app.state::<MenuState>();
             let menu = state.0.lock().unwrap();
             menu.clone()
         };
+        let totp_items = app.state::<TotpItems>().0.lock().unwrap().clone();
 
         // Update timer display
-        let timer_text = get_timer_display_text();
-        if let Some(menu_item) = menu_handle.get("timer") {
-            if let MenuItemKind::MenuItem(item) = menu_item {
+        let settings = read_settings(&app);
+        let periods = timer_periods(&totp_items);
+        for period in &periods {
+            let timer_text = get_timer_display_text(&settings, *period, periods.len() > 1);
+            let timer_id = format!("{}{}", TIMER_PREFIX, period);
+            if let Some(MenuItemKind::MenuItem(item)) = menu_handle.get(&timer_id) {
                 let _ = item.set_text(timer_text);
             }
         }
 
-        // Update OTP codes when period resets
-        if current_remaining_time > previous_remaining_time {
-            println!("OTP period reset detected, updating all OTP codes");
-
-            let token_ids = list_token_ids();
-            for id in &token_ids {
-                if let Some(menu_item) = menu_handle.get(id) {
-                    if let Ok(token) = read_token(id) {
-                        if let Ok(otp) = generate_otp(&token) {
-                            let text = get_otp_text(&id, &otp);
-                            if let MenuItemKind::MenuItem(item) = menu_item {
-                                let _ = item.set_text(text);
-                            }
-                        }
+        // Keep the codes of a pending import current for comparing with the authenticator app.
+        // Menu calls wait for the main thread, whose handlers lock the pending import too,
+        // so the lock must never be held across them.
+        let pending_texts: Vec<(String, String)> = {
+            let pending = app.state::<PendingImport>();
+            let mut pending = pending.0.lock().unwrap();
+            match pending.as_mut() {
+                Some(plan) => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|elapsed| elapsed.as_secs())
+                        .unwrap_or_default();
+                    plan.refresh_codes(now);
+                    plan.tokens
+                        .iter()
+                        .enumerate()
+                        .map(|(index, planned)| {
+                            (
+                                format!("{}{}", PENDING_TOKEN_PREFIX, index),
+                                get_pending_token_text(planned),
+                            )
+                        })
+                        .collect()
+                }
+                None => Vec::new(),
+            }
+        };
+        if !pending_texts.is_empty() {
+            if let Some(MenuItemKind::Submenu(pending_menu)) = menu_handle.get(PENDING_IMPORT_ID) {
+                for (id, text) in pending_texts {
+                    if let Some(MenuItemKind::Check(item)) = pending_menu.get(&id) {
+                        let _ = item.set_text(text);
                     }
                 }
             }
         }
 
-        previous_remaining_time = current_remaining_time;
+        // Update the OTP codes whose own period rolled over
+        for (id, totp_item) in &totp_items {
+            // Tracked codes never have a period of 0
+            let Some(step) = get_current_step(totp_item.period) else {
+                continue;
+            };
+            if step == totp_item.step {
+                continue;
+            }
+            println!("OTP period reset detected, updating {}", id);
+
+            let item_id = format!("{}{}", TOKEN_PREFIX, id);
+            if let Some(MenuItemKind::MenuItem(item)) = menu_handle.get(&item_id) {
+                if let Some(Ok(otp)) = menu_token(&app, id)
+                    .map(|token| token.and_then(|token| generate_token_otp(&token)))
+                {
+                    let _ = item.set_text(get_otp_text(id, &otp));
+                }
+            }
+
+            // The menu may have been rebuilt in the meantime
+            let state = app.state::<TotpItems>();
+            if let Some(current) = state.0.lock().unwrap().get_mut(id) {
+                if current.period == totp_item.period {
+                    current.step = step;
+                }
+            }
+        }
     }
 }
 
 fn reload_menu(app: &AppHandle) {
-    match list_token_ids() {
-        token_ids => {
-            match create_menu(app, &token_ids) {
-                Ok(new_menu) => {
-                    if let Some(tray) = app.tray_by_id("main") {
-                        if let Err(e) = tray.set_menu::<Menu<Wry>>(Some(new_menu.clone())) {
-                            eprintln!("Failed to update tray menu: {}", e);
-                        } else {
-                            // Update state
-                            let state = app.state::<MenuState>();
-                            *state.0.lock().unwrap() = new_menu;
-                            println!("Menu updated successfully");
-                        }
-                    } else {
-                        eprintln!("Main tray icon not found");
-                    }
+    // Re-resolve external secrets in case they changed
+    app.state::<SecretCache>().0.lock().unwrap().clear();
+    load_config(app);
+    rebuild_menu(app);
+}
+
+/// Replace the tray menu with one built from the config already loaded
+fn rebuild_menu(app: &AppHandle) {
+    let token_ids = list_token_ids(app);
+    match create_menu(app, &token_ids) {
+        Ok(new_menu) => {
+            if let Some(tray) = app.tray_by_id("main") {
+                if let Err(e) = tray.set_menu::<Menu<Wry>>(Some(new_menu.clone())) {
+                    eprintln!("Failed to update tray menu: {}", e);
+                } else {
+                    // Update state
+                    let state = app.state::<MenuState>();
+                    *state.0.lock().unwrap() = new_menu;
+                    println!("Menu updated successfully");
                 }
-                Err(e) => eprintln!("Failed to create new menu: {}", e),
+            } else {
+                eprintln!("Main tray icon not found");
             }
         }
+        Err(e) => eprintln!("Failed to create new menu: {}", e),
     }
 }
 
@@ -257,8 +1248,29 @@ pub fn run() {
         #[cfg(target_os = "macos")]
         app.set_activation_policy(ActivationPolicy::Accessory);
 
+        // Unlock the vault before anything reads the config
+        let store = match open_config_store() {
+            Ok(store) => store,
+            Err(e) => {
+                eprintln!("Failed to open config: {}", e);
+                std::process::exit(1);
+            }
+        };
+        app.manage(StoreState(Mutex::new(store)));
+        app.manage(SecretCache(Mutex::new(HashMap::new())));
+        app.manage(ResolvingSecrets(Mutex::new(HashSet::new())));
+        app.manage(ClipboardGeneration(AtomicU64::new(0)));
+        app.manage(ConfigState(Mutex::new(Config::default())));
+        app.manage(ConfigError(Mutex::new(None)));
+        app.manage(ConfigDiagnostics(Mutex::new(Vec::new())));
+        app.manage(ConfigWatcher(Mutex::new(None)));
+        app.manage(TotpItems(Mutex::new(HashMap::new())));
+        app.manage(PendingImport(Mutex::new(None)));
+        app.manage(ExportExclusions(Mutex::new(HashSet::new())));
+        load_config(app.handle());
+
         // Create initial menu
-        let token_ids = list_token_ids();
+        let token_ids = list_token_ids(app.handle());
         let menu = create_menu(app.handle(), &token_ids).expect("Failed to create menu");
 
         // Manage menu state
@@ -281,24 +1293,86 @@ pub fn run() {
                             eprintln!("Configuration error: {}", e);
                         }
                     });
+                } else if item_id == "configure_folder" {
+                    let app_clone = app.clone();
+                    tauri::async_runtime::spawn(async move {
+                        if let Err(e) = handle_configure_folder(app_clone).await {
+                            eprintln!("Configuration error: {}", e);
+                        }
+                    });
+                } else if item_id == "export" {
+                    let app_clone = app.clone();
+                    tauri::async_runtime::spawn(async move {
+                        if let Err(e) = handle_export(app_clone).await {
+                            eprintln!("Export error: {}", e);
+                        }
+                    });
+                } else if let Some(id) = item_id.strip_prefix(EXPORT_TOKEN_PREFIX) {
+                    toggle_export_token(app, id);
+                } else if item_id == "import_selected" {
+                    let app_clone = app.clone();
+                    tauri::async_runtime::spawn(async move {
+                        if let Err(e) = handle_import_selected(app_clone).await {
+                            eprintln!("Import error: {}", e);
+                        }
+                    });
+                } else if item_id == "cancel_import" {
+                    *app.state::<PendingImport>().0.lock().unwrap() = None;
+                    rebuild_menu(app);
+                } else if let Some(index) = item_id.strip_prefix(PENDING_TOKEN_PREFIX) {
+                    if let Ok(index) = index.parse::<usize>() {
+                        toggle_pending_token(app, index);
+                    }
+                } else if item_id == "encrypt_config" {
+                    let app_clone = app.clone();
+                    tauri::async_runtime::spawn(async move {
+                        if let Err(e) = handle_encrypt_config(app_clone).await {
+                            eprintln!("Failed to encrypt config: {}", e);
+                        }
+                    });
                 } else if item_id == "reload" {
                     println!("Reloading config...");
                     // let app_clone = app.clone();
                     // Reload config and update menu
                     reload_menu(app);
                 }else if item_id == "edit_config" {
-                    let config_path = get_config_file_path();
-                    let config_path_str = config_path.to_string_lossy().to_string();
-                    app.opener().open_path(config_path_str, None::<&str>)
-                        .map_err(|e| eprintln!("Failed to open config file: {}", e)).ok();
-
-                } else if item_id != "timer" {
-                    // It's a token ID
-                    let id = item_id.to_string();
+                    if let Err(e) = open_config_at(app, None) {
+                        eprintln!("Failed to open config file: {}", e);
+                    }
+                } else if let Some(index) = item_id.strip_prefix(DIAGNOSTIC_PREFIX) {
+                    let location = index.parse::<usize>().ok().and_then(|index| {
+                        let diagnostics = app.state::<ConfigDiagnostics>();
+                        let diagnostics = diagnostics.0.lock().unwrap();
+                        diagnostics.get(index).and_then(|diagnostic| diagnostic.location)
+                    });
+                    if let Err(e) = open_config_at(app, location) {
+                        eprintln!("Failed to open config file: {}", e);
+                    }
+                } else if let Some(name) = item_id.strip_prefix(RESTORE_PREFIX) {
+                    let name = name.to_string();
                     let app_clone = app.clone();
                     tauri::async_runtime::spawn(async move {
-                        if let Err(e) = copy_otp_to_clipboard(app_clone, id).await {
+                        if let Err(e) = handle_restore_backup(app_clone, name).await {
+                            eprintln!("Failed to restore backup: {}", e);
+                        }
+                    });
+                } else if let Some(id) = item_id.strip_prefix(SHOW_QR_PREFIX) {
+                    let id = id.to_string();
+                    let app_clone = app.clone();
+                    tauri::async_runtime::spawn(async move {
+                        if let Err(e) = handle_show_qr(app_clone, id).await {
+                            eprintln!("Failed to show QR code: {}", e);
+                        }
+                    });
+                } else if let Some(id) = item_id.strip_prefix(TOKEN_PREFIX) {
+                    let id = id.to_string();
+                    let app_clone = app.clone();
+                    tauri::async_runtime::spawn(async move {
+                        if let Err(e) = copy_otp_to_clipboard(app_clone.clone(), id.clone()).await
+                        {
                             eprintln!("Failed to copy OTP: {}", e);
+                            // Nothing was copied, show why in place of the code
+                            set_token_item_text(&app_clone, &id, format!("⚠️ {}: {}", id, e));
                         }
                     });
                 }
@@ -306,6 +1380,16 @@ pub fn run() {
             .build(app)
             .expect("Failed to create tray icon");
 
+        // Secrets resolved in the background before the tray existed could not update it
+        let resolved_early = app.state::<ResolvingSecrets>().0.lock().unwrap().is_empty()
+            && !app.state::<SecretCache>().0.lock().unwrap().is_empty();
+        if resolved_early {
+            rebuild_menu(app.handle());
+        }
+
+        // Reload the menu when the config is edited
+        watch_config(app.handle());
+
         // Start periodic update task
         let app_handle = app.handle().clone();
         tauri::async_runtime::spawn(async move {
@@ -0,0 +1,59 @@
+use crate::error::{Error, Result};
+use fs4::fs_std::FileExt;
+use std::fs::{self, File};
+use std::path::Path;
+
+/// Exclusive lock on the config, released when dropped
+#[derive(Debug)]
+pub struct ConfigFileLock {
+    _file: File,
+}
+
+/// Wait for and take the lock on the config stored in `path`.
+///
+/// The lock is only advisory and shared by all OTP Bar processes, e.g. the menu
+/// bar app and the command line. It is taken on a separate file next to the
+/// config, because saving replaces the config file itself. The plaintext config
+/// and the vault use the same lock file.
+pub fn lock_config(path: &Path) -> Result<ConfigFileLock> {
+    let lock_path = path.with_extension("lock");
+    if let Some(dir) = lock_path.parent() {
+        fs::create_dir_all(dir).map_err(|e| Error::io("create config directory", dir, e))?;
+    }
+
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| Error::io("open lock file", &lock_path, e))?;
+    file.lock_exclusive()
+        .map_err(|e| Error::io("lock", &lock_path, e))?;
+
+    Ok(ConfigFileLock { _file: file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_config() {
+        let dir = std::env::temp_dir().join("otp_bar_test_lock");
+        let _ = fs::remove_dir_all(&dir);
+        let config_path = dir.join("config.toml");
+
+        let lock = lock_config(&config_path).unwrap();
+        let other = File::open(dir.join("config.lock")).unwrap();
+        assert!(!other.try_lock_exclusive().unwrap());
+
+        // The vault shares the lock of the plaintext config
+        let vault_lock = File::open(dir.join("config.lock")).unwrap();
+        drop(lock);
+        assert!(vault_lock.try_lock_exclusive().unwrap());
+        drop(vault_lock);
+        drop(lock_config(&dir.join("config.vault")).unwrap());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
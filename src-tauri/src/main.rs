@@ -2,5 +2,9 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 fn main() {
+    if otp_bar_lib::cli::is_cli_invocation() {
+        std::process::exit(otp_bar_lib::cli::run());
+    }
+
     otp_bar_lib::run()
 }
@@ -1,46 +1,144 @@
+use crate::error::{Error, Result};
 use data_encoding::BASE32_NOPAD;
+use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
-use totp_lite::{totp_custom, Sha1};
+use totp_lite::{totp_custom, Sha1, Sha256, Sha512};
+
+pub const DEFAULT_DIGITS: u32 = 6;
+pub const DEFAULT_PERIOD: u64 = 30;
+
+/// HMAC hash function used to derive the code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Algorithm {
+    #[default]
+    #[serde(alias = "sha1")]
+    Sha1,
+    #[serde(alias = "sha256")]
+    Sha256,
+    #[serde(alias = "sha512")]
+    Sha512,
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "SHA1" => Ok(Algorithm::Sha1),
+            "SHA256" => Ok(Algorithm::Sha256),
+            "SHA512" => Ok(Algorithm::Sha512),
+            _ => Err(Error::InvalidParameter(format!(
+                "Unsupported algorithm: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Decode a base32 secret, ignoring case, spaces and padding as authenticator
+/// apps show secrets in groups like "JBSW Y3DP EHPK 3PXP"
+pub fn decode_secret(secret: &str) -> Result<Vec<u8>> {
+    let secret: String = secret
+        .chars()
+        .filter(|c| *c != '=' && !c.is_whitespace())
+        .collect();
+    Ok(BASE32_NOPAD.decode(secret.to_uppercase().as_bytes())?)
+}
+
+fn compute_code(
+    algorithm: Algorithm,
+    digits: u32,
+    step: u64,
+    secret_bytes: &[u8],
+    time: u64,
+) -> Result<String> {
+    if !(1..=9).contains(&digits) {
+        return Err(Error::InvalidParameter(format!(
+            "Unsupported number of digits: {}",
+            digits
+        )));
+    }
+    if step == 0 {
+        return Err(Error::InvalidParameter(
+            "Period must be greater than zero".to_string(),
+        ));
+    }
+
+    let code = match algorithm {
+        Algorithm::Sha1 => totp_custom::<Sha1>(step, digits, secret_bytes, time),
+        Algorithm::Sha256 => totp_custom::<Sha256>(step, digits, secret_bytes, time),
+        Algorithm::Sha512 => totp_custom::<Sha512>(step, digits, secret_bytes, time),
+    };
+
+    Ok(code)
+}
 
 /// Generate a TOTP code from a base32-encoded secret
-pub fn generate_otp(secret: &str) -> Result<String, String> {
+pub fn generate_otp(
+    secret: &str,
+    algorithm: Algorithm,
+    digits: u32,
+    period: u64,
+) -> Result<String> {
+    // Get current Unix timestamp
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    generate_otp_at(secret, algorithm, digits, period, timestamp)
+}
+
+/// Generate a TOTP code (RFC 6238) for the given Unix timestamp
+pub fn generate_otp_at(
+    secret: &str,
+    algorithm: Algorithm,
+    digits: u32,
+    period: u64,
+    timestamp: u64,
+) -> Result<String> {
     // Decode the base32 secret
-    let secret_bytes = BASE32_NOPAD
-        .decode(secret.to_uppercase().as_bytes())
-        .map_err(|e| format!("Failed to decode base32 secret: {}", e))?;
+    let secret_bytes = decode_secret(secret)?;
 
-    // Get current Unix timestamp
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map_err(|e| format!("Failed to get system time: {}", e))?
-        .as_secs();
+    compute_code(algorithm, digits, period, &secret_bytes, timestamp)
+}
 
-    // Generate TOTP code (30 second period, 6 digits)
-    let code = totp_custom::<Sha1>(30, 6, &secret_bytes, timestamp);
+/// Generate an HOTP code (RFC 4226) from a base32-encoded secret and counter
+pub fn generate_hotp(
+    secret: &str,
+    algorithm: Algorithm,
+    digits: u32,
+    counter: u64,
+) -> Result<String> {
+    let secret_bytes = decode_secret(secret)?;
 
-    Ok(code)
+    // HOTP is TOTP with a step of one, where the "time" is the counter itself
+    compute_code(algorithm, digits, 1, &secret_bytes, counter)
 }
 
-/// Calculate the remaining time in seconds for the current OTP period
-/// OTP typically refreshes every 30 seconds based on Unix time
-/// Returns remaining time in seconds (1-30, where 30 means start of new period)
-pub fn get_otp_remaining_time() -> u64 {
-    let now = SystemTime::now()
+fn unix_time() -> u64 {
+    SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards")
-        .as_secs();
+        .as_secs()
+}
 
-    let period = 30;
-    let time_in_period = now % period;
-    let remaining_time = period - time_in_period;
+/// Calculate the remaining time in seconds for the current step of a TOTP token
+/// Returns remaining time in seconds (1-period, where period means start of a new step),
+/// or `None` for a period of 0, which has no steps
+pub fn get_otp_remaining_time(period: u64) -> Option<u64> {
+    let time_in_period = unix_time().checked_rem(period)?;
+    Some(period - time_in_period)
+}
 
-    remaining_time
+/// Index of the current step of a TOTP token, which changes together with its code.
+/// `None` for a period of 0.
+pub fn get_current_step(period: u64) -> Option<u64> {
+    unix_time().checked_div(period)
 }
 
 /// Check if the OTP is in the warning period (last 10 seconds)
 /// Returns true if remaining time is <= 10 seconds
-pub fn is_otp_in_warning_period() -> bool {
-    get_otp_remaining_time() <= 10
+pub fn is_otp_in_warning_period(remaining_time: u64) -> bool {
+    remaining_time <= 10
 }
 
 #[cfg(test)]
@@ -49,14 +147,32 @@ mod tests {
 
     #[test]
     fn test_otp_remaining_time() {
-        let remaining = get_otp_remaining_time();
+        let remaining = get_otp_remaining_time(DEFAULT_PERIOD).unwrap();
         assert!(remaining > 0 && remaining <= 30);
+
+        let remaining = get_otp_remaining_time(60).unwrap();
+        assert!(remaining > 0 && remaining <= 60);
+    }
+
+    #[test]
+    fn test_current_step() {
+        let step = get_current_step(30).unwrap();
+        let step_60 = get_current_step(60).unwrap();
+        // Allow for the clock ticking between the two calls
+        assert!(step_60 == step / 2 || step_60 == step.div_ceil(2));
+    }
+
+    #[test]
+    fn test_zero_period() {
+        // A config may ask for a period of 0, which must not divide by zero
+        assert_eq!(get_otp_remaining_time(0), None);
+        assert_eq!(get_current_step(0), None);
     }
 
     #[test]
     fn test_warning_period() {
-        // This test just verifies the function runs without panicking
-        let _is_warning = is_otp_in_warning_period();
+        assert!(is_otp_in_warning_period(10));
+        assert!(!is_otp_in_warning_period(11));
     }
 
     #[test]
@@ -73,4 +189,123 @@ mod tests {
         let code = totp_custom::<Sha1>(30, 6, &secret_bytes, timestamp);
         assert_eq!(code, "005924", "Expected 005924, got {}", code);
     }
+
+    #[test]
+    fn test_hotp_rfc4226_vectors() {
+        // RFC 4226 Appendix D: secret "12345678901234567890", counters 0-9
+        let secret = BASE32_NOPAD.encode(b"12345678901234567890");
+        let expected = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(
+                generate_hotp(&secret, Algorithm::Sha1, 6, counter as u64).unwrap(),
+                *code,
+                "Mismatch at counter {}",
+                counter
+            );
+        }
+    }
+
+    #[test]
+    fn test_spaced_padded_secret() {
+        // The spelling the diagnostics accept must produce the same code
+        let secret = BASE32_NOPAD.encode(b"12345");
+        assert_eq!(secret, "GEZDGNBV");
+        assert_eq!(
+            generate_otp_at("gezd gnbv ==", Algorithm::Sha1, 6, 30, 59).unwrap(),
+            generate_otp_at(&secret, Algorithm::Sha1, 6, 30, 59).unwrap()
+        );
+        assert_eq!(
+            generate_hotp("JBSW Y3DP EHPK 3PXP", Algorithm::Sha1, 6, 0).unwrap(),
+            generate_hotp("JBSWY3DPEHPK3PXP", Algorithm::Sha1, 6, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hotp_invalid_secret() {
+        assert!(matches!(
+            generate_hotp("not base32!", Algorithm::Sha1, 6, 0),
+            Err(Error::InvalidSecret(_))
+        ));
+    }
+
+    #[test]
+    fn test_totp_rfc6238_vectors() {
+        // RFC 6238 Appendix B: 8 digits, 30 second period, one seed per hash
+        let sha1 = BASE32_NOPAD.encode(b"12345678901234567890");
+        let sha256 = BASE32_NOPAD.encode(b"12345678901234567890123456789012");
+        let sha512 = BASE32_NOPAD
+            .encode(b"1234567890123456789012345678901234567890123456789012345678901234");
+
+        let vectors: [(u64, &str, &str, &str); 6] = [
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (1111111111, "14050471", "67062674", "99943326"),
+            (1234567890, "89005924", "91819424", "93441116"),
+            (2000000000, "69279037", "90698825", "38618901"),
+            (20000000000, "65353130", "77737706", "47863826"),
+        ];
+
+        for (time, expected_sha1, expected_sha256, expected_sha512) in vectors {
+            assert_eq!(
+                generate_otp_at(&sha1, Algorithm::Sha1, 8, 30, time).unwrap(),
+                expected_sha1,
+                "SHA1 mismatch at {}",
+                time
+            );
+            assert_eq!(
+                generate_otp_at(&sha256, Algorithm::Sha256, 8, 30, time).unwrap(),
+                expected_sha256,
+                "SHA256 mismatch at {}",
+                time
+            );
+            assert_eq!(
+                generate_otp_at(&sha512, Algorithm::Sha512, 8, 30, time).unwrap(),
+                expected_sha512,
+                "SHA512 mismatch at {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn test_totp_custom_period() {
+        let secret = BASE32_NOPAD.encode(b"12345678901234567890");
+
+        // Both timestamps fall into the same 60 second window, but not the same 30 second one
+        let first = generate_otp_at(&secret, Algorithm::Sha1, 6, 60, 60).unwrap();
+        let second = generate_otp_at(&secret, Algorithm::Sha1, 6, 60, 119).unwrap();
+        assert_eq!(first, second);
+
+        // A 60 second period at time 60 uses the same counter as a 30 second one at time 30
+        let thirty = generate_otp_at(&secret, Algorithm::Sha1, 6, 30, 30).unwrap();
+        assert_eq!(first, thirty);
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        let secret = BASE32_NOPAD.encode(b"12345678901234567890");
+        assert!(matches!(
+            generate_otp_at(&secret, Algorithm::Sha1, 0, 30, 59),
+            Err(Error::InvalidParameter(_))
+        ));
+        assert!(generate_otp_at(&secret, Algorithm::Sha1, 10, 30, 59).is_err());
+        assert!(generate_otp_at(&secret, Algorithm::Sha1, 6, 0, 59).is_err());
+    }
+
+    #[test]
+    fn test_algorithm_serde() {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            algorithm: Algorithm,
+        }
+
+        let parsed: Wrapper = toml::from_str(r#"algorithm = "SHA256""#).unwrap();
+        assert_eq!(parsed.algorithm, Algorithm::Sha256);
+        let parsed: Wrapper = toml::from_str(r#"algorithm = "sha512""#).unwrap();
+        assert_eq!(parsed.algorithm, Algorithm::Sha512);
+    }
 }
@@ -0,0 +1,293 @@
+use crate::error::{Error, Result};
+use image::{DynamicImage, GrayImage, RgbImage};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use std::path::Path;
+
+/// File extension of the documents whose pages are scanned for QR codes
+pub const PDF_EXTENSION: &str = "pdf";
+
+/// Pages beyond this are not scanned, recovery kits are a few pages long
+const MAX_PAGES: usize = 50;
+
+/// Depth of nested form XObjects searched for images
+const MAX_FORM_DEPTH: usize = 4;
+
+/// Larger images are skipped, a QR code needs far fewer pixels
+const MAX_IMAGE_PIXELS: usize = 8192 * 8192;
+
+/// The images drawn on one page of a PDF
+pub struct PdfPage {
+    /// 1-based page number
+    pub number: u32,
+    pub images: Vec<DynamicImage>,
+}
+
+pub fn is_pdf(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(PDF_EXTENSION))
+}
+
+/// The images embedded in each page of the PDF at `path`. QR codes of recovery
+/// kits are embedded as images; codes drawn as vector graphics are not found.
+/// Images in encodings without a decoder here, such as JBIG2, are left out.
+pub fn page_images(path: &Path) -> Result<Vec<PdfPage>> {
+    let document = Document::load(path).map_err(|source| Error::Pdf {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    Ok(document
+        .get_pages()
+        .into_iter()
+        .take(MAX_PAGES)
+        .map(|(number, page_id)| PdfPage {
+            number,
+            images: images_of_page(&document, page_id),
+        })
+        .collect())
+}
+
+fn images_of_page(document: &Document, page_id: ObjectId) -> Vec<DynamicImage> {
+    let mut images = Vec::new();
+    let Ok((resources, resource_ids)) = document.get_page_resources(page_id) else {
+        return images;
+    };
+
+    // The page's own resources, then the ones inherited from the page tree
+    let inherited = resource_ids
+        .into_iter()
+        .filter_map(|id| document.get_dictionary(id).ok());
+    for resources in resources.into_iter().chain(inherited) {
+        collect_images(document, resources, 0, &mut images);
+    }
+    images
+}
+
+fn collect_images(
+    document: &Document,
+    resources: &Dictionary,
+    depth: usize,
+    images: &mut Vec<DynamicImage>,
+) {
+    let Ok(xobjects) = resources
+        .get(b"XObject")
+        .and_then(|object| resolve_dictionary(document, object))
+    else {
+        return;
+    };
+
+    for (_, object) in xobjects.iter() {
+        let Ok(stream) = object
+            .as_reference()
+            .and_then(|id| document.get_object(id))
+            .and_then(Object::as_stream)
+        else {
+            continue;
+        };
+
+        match stream.dict.get(b"Subtype").and_then(Object::as_name) {
+            Ok(b"Image") => images.extend(decode_image(document, stream)),
+            // Forms group content and may hold the image of a QR code
+            Ok(b"Form") if depth < MAX_FORM_DEPTH => {
+                if let Ok(resources) = stream
+                    .dict
+                    .get(b"Resources")
+                    .and_then(|object| resolve_dictionary(document, object))
+                {
+                    collect_images(document, resources, depth + 1, images);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn resolve_dictionary<'a>(
+    document: &'a Document,
+    object: &'a Object,
+) -> lopdf::Result<&'a Dictionary> {
+    document
+        .dereference(object)
+        .and_then(|(_, object)| object.as_dict())
+}
+
+/// Decode an image XObject. JPEG images are decoded by the `image` crate; other
+/// images are raw samples in a gray or RGB color space after decompression.
+fn decode_image(document: &Document, stream: &Stream) -> Option<DynamicImage> {
+    let filters = stream.filters().unwrap_or_default();
+    if filters == [b"DCTDecode".as_slice()] {
+        return image::load_from_memory(&stream.content).ok();
+    }
+
+    let width = dimension(&stream.dict, b"Width")?;
+    let height = dimension(&stream.dict, b"Height")?;
+    let pixels = (width as usize)
+        .checked_mul(height as usize)
+        .filter(|pixels| *pixels <= MAX_IMAGE_PIXELS)?;
+    let samples = stream.get_plain_content().ok()?;
+
+    // Stencil masks paint their 0 bits, like a black and white image
+    let image_mask = stream
+        .dict
+        .get(b"ImageMask")
+        .and_then(Object::as_bool)
+        .unwrap_or(false);
+    let (components, bits) = if image_mask {
+        (1, 1)
+    } else {
+        let bits = stream.dict.get(b"BitsPerComponent").ok()?.as_i64().ok()?;
+        (color_components(document, &stream.dict)?, bits)
+    };
+
+    match (components, bits) {
+        (1, 8) => GrayImage::from_raw(width, height, samples.get(..pixels)?.to_vec())
+            .map(DynamicImage::ImageLuma8),
+        (1, 1) => Some(DynamicImage::ImageLuma8(unpack_bits(
+            width, height, &samples,
+        )?)),
+        (3, 8) => RgbImage::from_raw(
+            width,
+            height,
+            samples.get(..pixels.checked_mul(3)?)?.to_vec(),
+        )
+        .map(DynamicImage::ImageRgb8),
+        _ => None,
+    }
+}
+
+fn dimension(dict: &Dictionary, key: &[u8]) -> Option<u32> {
+    dict.get(key)
+        .and_then(Object::as_i64)
+        .ok()
+        .and_then(|value| u32::try_from(value).ok())
+        .filter(|value| *value > 0)
+}
+
+/// Number of components of the image's color space, for the gray and RGB spaces
+fn color_components(document: &Document, dict: &Dictionary) -> Option<u32> {
+    let (_, color_space) = document.dereference(dict.get(b"ColorSpace").ok()?).ok()?;
+    match color_space {
+        Object::Name(name) => match name.as_slice() {
+            b"DeviceGray" | b"CalGray" => Some(1),
+            b"DeviceRGB" | b"CalRGB" => Some(3),
+            _ => None,
+        },
+        Object::Array(array) => match array.first()?.as_name().ok()? {
+            b"CalGray" => Some(1),
+            b"CalRGB" => Some(3),
+            b"ICCBased" => {
+                let (_, profile) = document.dereference(array.get(1)?).ok()?;
+                let components = profile
+                    .as_stream()
+                    .ok()?
+                    .dict
+                    .get(b"N")
+                    .ok()?
+                    .as_i64()
+                    .ok()?;
+                matches!(components, 1 | 3).then_some(components as u32)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Expand 1-bit samples, whose rows start on a byte boundary, to 8-bit gray
+fn unpack_bits(width: u32, height: u32, samples: &[u8]) -> Option<GrayImage> {
+    let row_bytes = width.div_ceil(8) as usize;
+    if samples.len() < row_bytes.checked_mul(height as usize)? {
+        return None;
+    }
+
+    Some(GrayImage::from_fn(width, height, |x, y| {
+        let byte = samples[y as usize * row_bytes + x as usize / 8];
+        let bit = (byte >> (7 - x % 8)) & 1;
+        image::Luma([if bit == 1 { 255 } else { 0 }])
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/pdf")
+            .join(name)
+    }
+
+    #[test]
+    fn test_is_pdf() {
+        assert!(is_pdf(Path::new("/tmp/recovery-kit.pdf")));
+        assert!(is_pdf(Path::new("KIT.PDF")));
+        assert!(!is_pdf(Path::new("qr.png")));
+        assert!(!is_pdf(Path::new("pdf")));
+    }
+
+    #[test]
+    fn test_read_missing_pdf() {
+        let path = std::env::temp_dir().join("otp_bar_test_missing.pdf");
+        let _ = std::fs::remove_file(&path);
+
+        assert!(matches!(page_images(&path), Err(Error::Pdf { .. })));
+    }
+
+    #[test]
+    fn test_page_images() {
+        let pages = page_images(&fixture("recovery-kit.pdf")).unwrap();
+
+        let counts: Vec<(u32, usize)> = pages
+            .iter()
+            .map(|page| (page.number, page.images.len()))
+            .collect();
+        // Instructions, a Flate compressed gray QR code, then a form with a
+        // JPEG QR code and a 1-bit stencil mask one
+        assert_eq!(counts, vec![(1, 0), (2, 1), (3, 2)]);
+        assert!(pages
+            .iter()
+            .flat_map(|page| &page.images)
+            .all(|image| image.width() >= 100 && image.height() >= 100));
+    }
+
+    #[test]
+    fn test_page_without_images() {
+        let pages = page_images(&fixture("vector-qr.pdf")).unwrap();
+
+        assert_eq!(pages.len(), 1);
+        assert!(pages[0].images.is_empty());
+    }
+
+    #[test]
+    fn test_decode_oversized_image() {
+        // Width times height overflows u32, the samples must not be sliced by it
+        let dict = dictionary! {
+            "Subtype" => "Image",
+            "Width" => 65536,
+            "Height" => 65536,
+            "ColorSpace" => "DeviceGray",
+            "BitsPerComponent" => 8,
+        };
+        let stream = Stream::new(dict, vec![0; 16]);
+
+        assert!(decode_image(&Document::new(), &stream).is_none());
+    }
+
+    #[test]
+    fn test_unpack_bits() {
+        // 10 pixels wide, so each row takes 2 bytes
+        let image = unpack_bits(10, 2, &[0b1010_0000, 0b0100_0000, 0xff, 0xc0]).unwrap();
+        let row = |y| {
+            (0..10)
+                .map(|x| image.get_pixel(x, y)[0])
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(row(0), vec![255, 0, 255, 0, 0, 0, 0, 0, 0, 255]);
+        assert_eq!(row(1), vec![255; 10]);
+        assert!(unpack_bits(10, 3, &[0; 4]).is_none());
+    }
+}
@@ -0,0 +1,332 @@
+use crate::error::{Error, Result};
+use image::imageops::{self, FilterType};
+use image::{GrayImage, Luma};
+
+/// Images with a shorter side below this are enlarged before detection, so
+/// that each module of a small code covers several pixels
+const MIN_SIZE: u32 = 400;
+
+/// Upper limit of the enlargement of small images
+const MAX_SCALE: u32 = 8;
+
+/// Share of the darkest and brightest pixels ignored when stretching the contrast,
+/// so a few specks of glare or dust do not decide the range
+const CONTRAST_CLIP_PERCENT: u64 = 1;
+
+/// How much darker than its surroundings a pixel must be to count as a dark module
+const THRESHOLD_PERCENT: u64 = 15;
+
+/// One way of changing an image before it is handed to the QR code detector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Stretch the brightness range to the full 0-255, e.g. for photos of a monitor
+    NormalizeContrast,
+    /// Swap dark and light, for dark mode codes with light modules on a dark background
+    Invert,
+    /// Enlarge small screenshots with nearest neighbour sampling
+    Upscale,
+    /// Turn each pixel black or white depending on its surroundings, for uneven lighting
+    AdaptiveThreshold,
+    /// Rotate by the given number of degrees, counterclockwise
+    Rotate(i32),
+}
+
+use Step::*;
+
+/// Variants of the image tried in order, until one of them decodes. The
+/// unchanged image comes first and the expensive variants last.
+const PIPELINE: &[&[Step]] = &[
+    &[],
+    &[NormalizeContrast],
+    &[NormalizeContrast, Invert],
+    &[Upscale, NormalizeContrast],
+    &[Upscale, NormalizeContrast, Invert],
+    &[Upscale, AdaptiveThreshold],
+    &[Upscale, AdaptiveThreshold, Invert],
+    &[Upscale, AdaptiveThreshold, Rotate(15)],
+    &[Upscale, AdaptiveThreshold, Rotate(-15)],
+    &[Upscale, AdaptiveThreshold, Rotate(30)],
+    &[Upscale, AdaptiveThreshold, Rotate(-30)],
+    &[Upscale, AdaptiveThreshold, Rotate(45)],
+];
+
+/// Find and decode the QR codes in `img`, preparing it in the ways of `PIPELINE`
+/// until every code that was found could be decoded. Returns the content or the
+/// decoding error of each code found by the attempt that decoded the most.
+pub fn decode_qr_codes(img: &GrayImage) -> Result<Vec<Result<String>>> {
+    let mut best: Option<(usize, Vec<Result<String>>)> = None;
+
+    for steps in variants(img) {
+        let mut prepared = rqrr::PreparedImage::prepare(apply(img, &steps));
+        let codes: Vec<Result<String>> = prepared
+            .detect_grids()
+            .iter()
+            .map(|grid| Ok(grid.decode()?.1))
+            .collect();
+        let decoded = codes.iter().filter(|code| code.is_ok()).count();
+
+        if decoded > 0 && decoded == codes.len() {
+            if !steps.is_empty() {
+                eprintln!("Decoded QR code after {:?}", steps);
+            }
+            return Ok(codes);
+        }
+        let better = match &best {
+            Some((best_decoded, _)) => decoded > *best_decoded,
+            None => !codes.is_empty(),
+        };
+        if better {
+            best = Some((decoded, codes));
+        }
+    }
+
+    best.map(|(_, codes)| codes).ok_or(Error::NoQrCode)
+}
+
+/// The steps of `PIPELINE` that change `img`. Enlarging is left out for images
+/// that are large enough, and variants that become the same as an earlier one are skipped.
+fn variants(img: &GrayImage) -> Vec<Vec<Step>> {
+    let upscale = scale_factor(img) > 1;
+    let mut variants: Vec<Vec<Step>> = Vec::new();
+
+    for steps in PIPELINE {
+        let steps: Vec<Step> = steps
+            .iter()
+            .copied()
+            .filter(|step| upscale || *step != Upscale)
+            .collect();
+        if !variants.contains(&steps) {
+            variants.push(steps);
+        }
+    }
+
+    variants
+}
+
+/// Apply `steps` to a copy of `img` in order
+pub fn apply(img: &GrayImage, steps: &[Step]) -> GrayImage {
+    let mut img = img.clone();
+    for step in steps {
+        img = match step {
+            NormalizeContrast => normalize_contrast(&img),
+            Invert => {
+                imageops::invert(&mut img);
+                img
+            }
+            Upscale => upscale(&img),
+            AdaptiveThreshold => adaptive_threshold(&img),
+            Rotate(degrees) => rotate(&img, *degrees as f32),
+        };
+    }
+    img
+}
+
+fn normalize_contrast(img: &GrayImage) -> GrayImage {
+    let mut histogram = [0u64; 256];
+    for pixel in img.pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+
+    let clip = img.width() as u64 * img.height() as u64 * CONTRAST_CLIP_PERCENT / 100;
+    let low = percentile(histogram.iter().enumerate(), clip).unwrap_or(0);
+    let high = percentile(histogram.iter().enumerate().rev(), clip).unwrap_or(255);
+    if high <= low {
+        return img.clone();
+    }
+
+    let range = (high - low) as u32;
+    GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        let value = img.get_pixel(x, y)[0].clamp(low, high) - low;
+        Luma([(value as u32 * 255 / range) as u8])
+    })
+}
+
+/// First brightness at which more than `clip` pixels have been counted
+fn percentile<'a>(mut histogram: impl Iterator<Item = (usize, &'a u64)>, clip: u64) -> Option<u8> {
+    let mut count = 0;
+    histogram
+        .find(|(_, pixels)| {
+            count += **pixels;
+            count > clip
+        })
+        .map(|(value, _)| value as u8)
+}
+
+/// How many times larger `img` should be made
+fn scale_factor(img: &GrayImage) -> u32 {
+    let shorter = img.width().min(img.height()).max(1);
+    MIN_SIZE.div_ceil(shorter).min(MAX_SCALE)
+}
+
+fn upscale(img: &GrayImage) -> GrayImage {
+    let factor = scale_factor(img);
+    if factor <= 1 {
+        return img.clone();
+    }
+    imageops::resize(
+        img,
+        img.width() * factor,
+        img.height() * factor,
+        FilterType::Nearest,
+    )
+}
+
+/// Compare each pixel with the mean of a square around it (Bradley-Roth).
+/// The square spans about an eighth of the image, several modules of a code filling it.
+fn adaptive_threshold(img: &GrayImage) -> GrayImage {
+    let (width, height) = img.dimensions();
+    let radius = (width.max(height) / 16).max(1);
+
+    // Sums of all pixels above and to the left, with a row and column of zeros
+    let stride = width as usize + 1;
+    let mut integral = vec![0u64; stride * (height as usize + 1)];
+    for y in 0..height as usize {
+        let mut row_sum = 0;
+        for x in 0..width as usize {
+            row_sum += img.get_pixel(x as u32, y as u32)[0] as u64;
+            integral[(y + 1) * stride + x + 1] = integral[y * stride + x + 1] + row_sum;
+        }
+    }
+
+    GrayImage::from_fn(width, height, |x, y| {
+        let left = x.saturating_sub(radius) as usize;
+        let top = y.saturating_sub(radius) as usize;
+        let right = (x + radius + 1).min(width) as usize;
+        let bottom = (y + radius + 1).min(height) as usize;
+
+        let count = ((right - left) * (bottom - top)) as u64;
+        let sum = integral[bottom * stride + right] + integral[top * stride + left]
+            - integral[top * stride + right]
+            - integral[bottom * stride + left];
+
+        let value = img.get_pixel(x, y)[0] as u64;
+        if value * count * 100 <= sum * (100 - THRESHOLD_PERCENT) {
+            Luma([0])
+        } else {
+            Luma([255])
+        }
+    })
+}
+
+/// Rotate around the centre onto a canvas large enough for the whole image,
+/// filling the corners with white
+fn rotate(img: &GrayImage, degrees: f32) -> GrayImage {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (width, height) = (img.width() as f32, img.height() as f32);
+    let new_width = (width * cos.abs() + height * sin.abs()).round() as u32;
+    let new_height = (width * sin.abs() + height * cos.abs()).round() as u32;
+    let (center_x, center_y) = (width / 2.0, height / 2.0);
+    let (new_center_x, new_center_y) = (new_width as f32 / 2.0, new_height as f32 / 2.0);
+
+    GrayImage::from_fn(new_width, new_height, |x, y| {
+        // Pixel of the original image that ends up at (x, y)
+        let dx = x as f32 + 0.5 - new_center_x;
+        let dy = y as f32 + 0.5 - new_center_y;
+        let source_x = cos * dx - sin * dy + center_x;
+        let source_y = sin * dx + cos * dy + center_y;
+        Luma([sample(img, source_x, source_y)])
+    })
+}
+
+/// Bilinear interpolation at a point given in pixel coordinates, white outside the image
+fn sample(img: &GrayImage, x: f32, y: f32) -> u8 {
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+
+    let pixel = |px: f32, py: f32| -> f32 {
+        if px < 0.0 || py < 0.0 || px >= img.width() as f32 || py >= img.height() as f32 {
+            255.0
+        } else {
+            img.get_pixel(px as u32, py as u32)[0] as f32
+        }
+    };
+
+    let top = pixel(x0, y0) * (1.0 - fx) + pixel(x0 + 1.0, y0) * fx;
+    let bottom = pixel(x0, y0 + 1.0) * (1.0 - fx) + pixel(x0 + 1.0, y0 + 1.0) * fx;
+    (top * (1.0 - fy) + bottom * fy).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_contrast() {
+        // A dim picture of a screen, everything between 100 and 150
+        let img = GrayImage::from_fn(100, 100, |x, _| Luma([if x < 50 { 100 } else { 150 }]));
+
+        let normalized = normalize_contrast(&img);
+        assert_eq!(normalized.get_pixel(0, 0)[0], 0);
+        assert_eq!(normalized.get_pixel(99, 0)[0], 255);
+
+        // A uniform image has no contrast to stretch
+        let flat = GrayImage::from_pixel(10, 10, Luma([128]));
+        assert_eq!(normalize_contrast(&flat), flat);
+    }
+
+    #[test]
+    fn test_adaptive_threshold_evens_out_lighting() {
+        // Dark squares on a background that gets brighter from left to right
+        let img = GrayImage::from_fn(160, 160, |x, y| {
+            let background = 60 + (x * 190 / 160) as u8;
+            let module = (x / 10 + y / 10) % 2 == 0;
+            Luma([if module { background / 2 } else { background }])
+        });
+
+        let binary = adaptive_threshold(&img);
+        assert!(binary
+            .pixels()
+            .all(|pixel| pixel[0] == 0 || pixel[0] == 255));
+        // Modules are dark on both sides, although the right module is brighter
+        // than the left background
+        assert_eq!(binary.get_pixel(5, 85)[0], 0);
+        assert_eq!(binary.get_pixel(145, 85)[0], 0);
+        assert_eq!(binary.get_pixel(15, 85)[0], 255);
+        assert_eq!(binary.get_pixel(155, 85)[0], 255);
+    }
+
+    #[test]
+    fn test_upscale_and_rotate() {
+        let tiny = GrayImage::from_fn(40, 30, |x, _| Luma([if x < 20 { 0 } else { 255 }]));
+        let large = upscale(&tiny);
+        assert_eq!(large.dimensions(), (320, 240));
+        assert_eq!(large.get_pixel(159, 0)[0], 0);
+        assert_eq!(large.get_pixel(160, 0)[0], 255);
+
+        let big = GrayImage::new(MIN_SIZE, MIN_SIZE * 2);
+        assert_eq!(scale_factor(&big), 1);
+        assert_eq!(upscale(&big).dimensions(), big.dimensions());
+
+        // A quarter turn swaps the sides, a dark left half ends up at the bottom
+        let turned = rotate(&tiny, 90.0);
+        assert_eq!(turned.dimensions(), (30, 40));
+        assert_eq!(turned.get_pixel(15, 35)[0], 0);
+        assert_eq!(turned.get_pixel(15, 5)[0], 255);
+
+        // The corners uncovered by an oblique rotation are white
+        let square = GrayImage::from_pixel(100, 100, Luma([0]));
+        let tilted = rotate(&square, 45.0);
+        assert_eq!(tilted.dimensions(), (141, 141));
+        assert_eq!(tilted.get_pixel(0, 0)[0], 255);
+        assert_eq!(tilted.get_pixel(71, 71)[0], 0);
+    }
+
+    #[test]
+    fn test_variants() {
+        let small = GrayImage::new(100, 100);
+        let variants = variants(&small);
+        assert_eq!(variants.len(), PIPELINE.len());
+        assert_eq!(variants[0], Vec::<Step>::new());
+
+        // Enlarging a large image changes nothing, so those variants are left out
+        let large = GrayImage::new(MIN_SIZE, MIN_SIZE);
+        let variants = super::variants(&large);
+        assert_eq!(variants.len(), PIPELINE.len() - 2);
+        assert!(variants.iter().all(|steps| !steps.contains(&Upscale)));
+        assert!(variants.contains(&vec![AdaptiveThreshold, Rotate(45)]));
+
+        let inverted = apply(&GrayImage::from_pixel(2, 2, Luma([10])), &[Invert]);
+        assert_eq!(inverted.get_pixel(0, 0)[0], 245);
+    }
+}
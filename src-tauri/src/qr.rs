@@ -1,218 +1,772 @@
+use crate::config::{self, Config, ImportOutcome, ImportPolicy, ImportedToken, TokenType};
+use crate::error::{Error, Result};
+use crate::otp::{
+    decode_secret, generate_hotp, generate_otp_at, Algorithm, DEFAULT_DIGITS, DEFAULT_PERIOD,
+};
+use crate::{pdf, preprocess};
 use data_encoding::BASE32_NOPAD;
-use rqrr;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone)]
+/// File extensions accepted for QR code import, the image formats and PDF
+pub const IMPORT_EXTENSIONS: &[&str] = &[
+    "png",
+    "jpg",
+    "jpeg",
+    "webp",
+    "gif",
+    "bmp",
+    "tif",
+    "tiff",
+    pdf::PDF_EXTENSION,
+];
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct TokenData {
     pub name: String,
     pub secret: String,
+    pub issuer: Option<String>,
+    pub token_type: TokenType,
+    pub algorithm: Option<Algorithm>,
+    pub digits: Option<u32>,
+    pub period: Option<u64>,
+    pub counter: Option<u64>,
+    /// Set for tokens imported from a Google Authenticator export
+    pub batch: Option<BatchInfo>,
 }
 
-/// Parse a QR code image and extract OTP tokens
-pub fn parse_qr_and_extract_tokens(image_path: &str) -> Result<Vec<TokenData>, String> {
-    // Load the image
-    let img = image::open(image_path).map_err(|e| format!("Failed to open image: {}", e))?;
+impl TokenData {
+    /// Name used as the token key in the config, e.g. "GitHub (alice)"
+    pub fn display_name(&self) -> String {
+        match &self.issuer {
+            Some(issuer) if self.name.is_empty() || self.name == *issuer => issuer.clone(),
+            Some(issuer) => format!("{} ({})", issuer, self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Inverse of `display_name` and `to_config_token`
+    pub fn from_config(name: &str, token: &config::TokenData) -> Self {
+        let account = match &token.issuer {
+            Some(issuer) if name == issuer => String::new(),
+            Some(issuer) => name
+                .strip_prefix(&format!("{} (", issuer))
+                .and_then(|rest| rest.strip_suffix(')'))
+                .unwrap_or(name)
+                .to_string(),
+            None => name.to_string(),
+        };
+
+        TokenData {
+            name: account,
+            secret: token.secret.clone(),
+            issuer: token.issuer.clone(),
+            token_type: token.token_type,
+            algorithm: token.algorithm,
+            digits: token.digits,
+            period: token.period,
+            counter: token.counter,
+            batch: None,
+        }
+    }
+
+    pub fn to_config_token(&self) -> config::TokenData {
+        config::TokenData {
+            secret: self.secret.clone(),
+            issuer: self.issuer.clone(),
+            token_type: self.token_type,
+            counter: match self.token_type {
+                TokenType::Hotp => Some(self.counter.unwrap_or(0)),
+                TokenType::Totp => None,
+            },
+            algorithm: self.algorithm,
+            digits: self.digits,
+            period: self.period,
+            ..Default::default()
+        }
+    }
+}
+
+/// Tokens read from one image. QR codes and migration entries that cannot be
+/// read are listed in `failures` and do not keep the others from being imported.
+#[derive(Debug, Default)]
+pub struct ImageImport {
+    pub tokens: Vec<TokenData>,
+    /// e.g. "QR code 2: QR code does not contain OTP data"
+    pub failures: Vec<String>,
+}
+
+/// Parse a QR code image, or the images on every page of a PDF, and extract OTP
+/// tokens. Fails if the file holds no token at all, with the reason of each QR
+/// code that failed.
+pub fn parse_qr_and_extract_tokens(image_path: &str) -> Result<ImageImport> {
+    let pages = load_pages(Path::new(image_path))?;
+
+    let mut import = ImageImport::default();
+    let mut found_code = false;
+
+    for page in &pages {
+        // Find and decode QR codes in the grayscale images, retrying with
+        // enhanced versions of screenshots and photos that are hard to read.
+        // Images without a code, such as the logo on a recovery kit, are fine.
+        let mut codes = Vec::new();
+        for image in &page.images {
+            match preprocess::decode_qr_codes(&image.to_luma8()) {
+                Ok(found) => codes.extend(found),
+                Err(Error::NoQrCode) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if codes.is_empty() {
+            continue;
+        }
+        found_code = true;
+
+        let page_label = match page.number {
+            Some(number) if pages.len() > 1 => format!("page {}, ", number),
+            _ => String::new(),
+        };
+        for (index, code) in codes.into_iter().enumerate() {
+            let label = format!("{}QR code {}", page_label, index + 1);
+            match code.and_then(|content| parse_qr_content(&content)) {
+                Ok(payload) => {
+                    import.tokens.extend(payload.tokens);
+                    import.failures.extend(
+                        payload
+                            .skipped
+                            .iter()
+                            .map(|entry| format!("{}, {}", label, entry)),
+                    );
+                }
+                Err(e) => import.failures.push(format!("{}: {}", label, e)),
+            }
+        }
+    }
+
+    if !found_code {
+        // Only the images embedded in a PDF are scanned, tell why a visible code was missed
+        return Err(if pdf::is_pdf(Path::new(image_path)) {
+            Error::NoQrCodeInPdf
+        } else {
+            Error::NoQrCode
+        });
+    }
 
-    // Convert to luma (grayscale)
-    let img = img.to_luma8();
+    if import.tokens.is_empty() {
+        return Err(Error::InvalidOtpData(if import.failures.is_empty() {
+            "No tokens found in QR code".to_string()
+        } else {
+            import.failures.join("; ")
+        }));
+    }
 
-    // Prepare the image for rqrr
-    let mut img_for_qr = rqrr::PreparedImage::prepare(img);
+    Ok(import)
+}
 
-    // Find and decode QR codes
-    let grids = img_for_qr.detect_grids();
+/// Images scanned for QR codes, with the number of the PDF page they are on
+struct Page {
+    number: Option<u32>,
+    images: Vec<image::DynamicImage>,
+}
 
-    if grids.is_empty() {
-        return Err("No QR code found in image".to_string());
+/// The pages of a PDF, or the image itself for the formats of the `image` crate
+fn load_pages(path: &Path) -> Result<Vec<Page>> {
+    if pdf::is_pdf(path) {
+        return Ok(pdf::page_images(path)?
+            .into_iter()
+            .map(|page| Page {
+                number: Some(page.number),
+                images: page.images,
+            })
+            .collect());
     }
 
-    let mut all_tokens = Vec::new();
+    let img = image::open(path).map_err(|source| Error::Image {
+        action: "open",
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(vec![Page {
+        number: None,
+        images: vec![img],
+    }])
+}
+
+/// Tokens in the text of a single QR code. A plain `otpauth://` URL gives a
+/// payload with just that token.
+fn parse_qr_content(content: &str) -> Result<MigrationPayload> {
+    // Try to parse as otpauth-migration URL
+    if content.starts_with("otpauth-migration://") {
+        let payload = parse_migration_url(content)?;
+        eprintln!(
+            "Migration batch {}/{} (id {}, version {}) with {} token(s)",
+            payload.batch_index + 1,
+            payload.batch_size,
+            payload.batch_id,
+            payload.version,
+            payload.tokens.len()
+        );
+        Ok(payload)
+    } else if content.starts_with("otpauth://") {
+        // Single OTP URL
+        Ok(MigrationPayload {
+            tokens: vec![parse_otpauth_url(content)?],
+            ..Default::default()
+        })
+    } else {
+        Err(Error::InvalidOtpData(
+            "QR code does not contain OTP data".to_string(),
+        ))
+    }
+}
+
+/// Tokens collected from several QR code images
+#[derive(Debug, Default)]
+pub struct BatchImport {
+    pub tokens: Vec<TokenData>,
+    /// Images that could not be read and incomplete Google Authenticator exports
+    pub warnings: Vec<String>,
+}
+
+/// Parse several QR code images at once. Images of a split Google Authenticator
+/// export are grouped by their batch id, and missing parts are reported as warnings.
+pub fn parse_qr_images(image_paths: &[PathBuf]) -> Result<BatchImport> {
+    let mut import = BatchImport::default();
+    let mut seen_batches = HashSet::new();
+
+    for path in image_paths {
+        let image = match parse_qr_and_extract_tokens(&path.to_string_lossy()) {
+            Ok(image) => image,
+            Err(e) => {
+                import.warnings.push(format!("{}: {}", path.display(), e));
+                continue;
+            }
+        };
+
+        // The same part of an export may be selected twice, e.g. as a photo and a screenshot.
+        // Single exports leave the batch unset, so all of them would look like part 1 of
+        // export 0; only parts of a split export are told apart by their batch.
+        let image_batches: HashSet<(i32, i32)> = image
+            .tokens
+            .iter()
+            .filter_map(|token| token.batch)
+            .filter(|batch| batch.size > 0)
+            .map(|batch| (batch.id, batch.index))
+            .collect();
+        if !image_batches.is_empty() && image_batches.is_subset(&seen_batches) {
+            eprintln!("Skipping duplicate export part in {}", path.display());
+            continue;
+        }
+        seen_batches.extend(image_batches);
 
-    for grid in grids {
-        let (_meta, content) = grid
-            .decode()
-            .map_err(|e| format!("Failed to decode QR code: {:?}", e))?;
+        import.tokens.extend(image.tokens);
+        import.warnings.extend(
+            image
+                .failures
+                .iter()
+                .map(|failure| format!("{}: {}", path.display(), failure)),
+        );
+    }
 
-        println!("QR Code content: {}", content);
+    for (id, size, missing) in missing_batch_indexes(&import.tokens) {
+        let missing = missing
+            .iter()
+            .map(|index| (index + 1).to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        import.warnings.push(format!(
+            "Export {} is incomplete: QR code(s) {} of {} not found",
+            id, missing, size
+        ));
+    }
 
-        // Try to parse as otpauth-migration URL
-        if content.starts_with("otpauth-migration://") {
-            let tokens = parse_migration_url(&content)?;
-            all_tokens.extend(tokens);
-        } else if content.starts_with("otpauth://") {
-            // Single OTP URL
-            let token = parse_otpauth_url(&content)?;
-            all_tokens.push(token);
+    if import.tokens.is_empty() {
+        return Err(Error::InvalidOtpData(if import.warnings.is_empty() {
+            "No tokens found in the selected images".to_string()
         } else {
-            return Err("QR code does not contain OTP data".to_string());
+            import.warnings.join("\n")
+        }));
+    }
+
+    Ok(import)
+}
+
+/// Group tokens by migration batch id and list the batch indexes that were not seen.
+/// Returns `(batch_id, batch_size, missing_indexes)` for every incomplete batch.
+pub fn missing_batch_indexes(tokens: &[TokenData]) -> Vec<(i32, i32, Vec<i32>)> {
+    let mut batches: BTreeMap<i32, (i32, BTreeSet<i32>)> = BTreeMap::new();
+
+    for batch in tokens.iter().filter_map(|token| token.batch) {
+        let (size, indexes) = batches.entry(batch.id).or_default();
+        *size = (*size).max(batch.size);
+        indexes.insert(batch.index);
+    }
+
+    batches
+        .into_iter()
+        .filter_map(|(id, (size, indexes))| {
+            let missing: Vec<i32> = (0..size).filter(|i| !indexes.contains(i)).collect();
+            (!missing.is_empty()).then_some((id, size, missing))
+        })
+        .collect()
+}
+
+/// A decoded token in an import plan
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedToken {
+    pub token: TokenData,
+    /// Name and outcome the token would get if it was imported now
+    pub import: ImportedToken,
+    /// Code of the token, to compare with the authenticator app before importing.
+    /// HOTP tokens show the code of their current counter.
+    pub code: std::result::Result<String, String>,
+    pub selected: bool,
+}
+
+impl PlannedToken {
+    /// Whether importing writes the token. Duplicates and taken names are left
+    /// out by the import policy, so they cannot be selected.
+    pub fn can_import(&self) -> bool {
+        !matches!(
+            self.import.outcome,
+            ImportOutcome::Skipped | ImportOutcome::Duplicate { .. }
+        )
+    }
+}
+
+/// Decoded tokens waiting for the user to choose which ones to import
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportPlan {
+    pub tokens: Vec<PlannedToken>,
+    /// Images that could not be read and incomplete Google Authenticator exports
+    pub warnings: Vec<String>,
+}
+
+impl ImportPlan {
+    pub fn selected_tokens(&self) -> Vec<TokenData> {
+        self.tokens
+            .iter()
+            .filter(|planned| planned.selected)
+            .map(|planned| planned.token.clone())
+            .collect()
+    }
+
+    pub fn selected_count(&self) -> usize {
+        self.tokens
+            .iter()
+            .filter(|planned| planned.selected)
+            .count()
+    }
+
+    /// Recalculate the codes for the Unix timestamp `time`
+    pub fn refresh_codes(&mut self, time: u64) {
+        for planned in &mut self.tokens {
+            planned.code = preview_code(&planned.token, time).map_err(|e| e.to_string());
         }
     }
+}
+
+/// Work out what importing the tokens of `import` into `config` would do, without
+/// changing the config. Tokens that would be skipped start out deselected.
+pub fn plan_import(
+    import: BatchImport,
+    config: &Config,
+    policy: ImportPolicy,
+    time: u64,
+) -> ImportPlan {
+    let mut preview = config.clone();
+    let tokens = import
+        .tokens
+        .into_iter()
+        .map(|token| {
+            let import =
+                preview.import_token(token.display_name(), token.to_config_token(), policy);
+            let mut planned = PlannedToken {
+                code: preview_code(&token, time).map_err(|e| e.to_string()),
+                token,
+                import,
+                selected: false,
+            };
+            planned.selected = planned.can_import();
+            planned
+        })
+        .collect();
 
-    if all_tokens.is_empty() {
-        return Err("No tokens found in QR code".to_string());
+    ImportPlan {
+        tokens,
+        warnings: import.warnings,
     }
+}
 
-    Ok(all_tokens)
+/// Code of a decoded token at the Unix timestamp `time`
+fn preview_code(token: &TokenData, time: u64) -> Result<String> {
+    let token = token.to_config_token();
+    match token.token_type {
+        TokenType::Totp => generate_otp_at(
+            &token.secret,
+            token.algorithm(),
+            token.digits(),
+            token.period(),
+            time,
+        ),
+        TokenType::Hotp => generate_hotp(
+            &token.secret,
+            token.algorithm(),
+            token.digits(),
+            token.counter.unwrap_or(0),
+        ),
+    }
 }
 
-fn parse_otpauth_url(url: &str) -> Result<TokenData, String> {
-    // Parse otpauth://totp/AccountName?secret=BASE32SECRET&issuer=Issuer
-    let url = url::Url::parse(url).map_err(|e| format!("Failed to parse URL: {}", e))?;
+/// List the importable images and PDFs directly inside a folder, sorted by file name
+pub fn image_files_in_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = fs::read_dir(dir).map_err(|e| Error::io("read folder", dir, e))?;
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| IMPORT_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                .unwrap_or(false)
+        })
+        .collect();
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Split an "Issuer:Account" label into its issuer prefix and account name
+fn split_label(label: &str) -> (Option<String>, String) {
+    match label.split_once(':') {
+        Some((issuer, account)) => (Some(issuer.trim().to_string()), account.trim().to_string()),
+        None => (None, label.trim().to_string()),
+    }
+}
+
+fn parse_otpauth_url(url: &str) -> Result<TokenData> {
+    // Parse otpauth://TYPE/Issuer:Account?secret=BASE32SECRET&issuer=Issuer&...
+    // See https://github.com/google/google-authenticator/wiki/Key-Uri-Format
+    let url = url::Url::parse(url)?;
+
+    let token_type = match url.host_str().map(|h| h.to_lowercase()).as_deref() {
+        Some("totp") => TokenType::Totp,
+        Some("hotp") => TokenType::Hotp,
+        Some(other) => {
+            return Err(Error::InvalidOtpData(format!(
+                "Unsupported OTP type: {}",
+                other
+            )))
+        }
+        None => {
+            return Err(Error::InvalidOtpData(
+                "No OTP type found in URL".to_string(),
+            ))
+        }
+    };
 
     let path = url.path().trim_start_matches('/');
-    let name = urlencoding::decode(path)
-        .map_err(|e| format!("Failed to decode name: {}", e))?
+    let label = urlencoding::decode(path)
+        .map_err(Error::InvalidLabel)?
         .to_string();
+    let (label_issuer, name) = split_label(&label);
 
-    let secret = url
-        .query_pairs()
-        .find(|(key, _)| key == "secret")
-        .map(|(_, value)| value.to_string())
-        .ok_or_else(|| "No secret found in URL".to_string())?;
+    let mut token = TokenData {
+        name,
+        issuer: label_issuer,
+        token_type,
+        ..Default::default()
+    };
 
-    Ok(TokenData { name, secret })
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "secret" => {
+                token.secret = value
+                    .chars()
+                    .filter(|c| *c != '=' && !c.is_whitespace())
+                    .collect::<String>()
+                    .to_uppercase();
+            }
+            // The issuer parameter is preferred over the label prefix
+            "issuer" if !value.is_empty() => token.issuer = Some(value.to_string()),
+            "algorithm" => token.algorithm = Some(value.parse()?),
+            "digits" => {
+                token.digits = Some(parse_number("digits", &value)?);
+            }
+            "period" => {
+                token.period = Some(parse_number("period", &value)?);
+            }
+            "counter" => {
+                token.counter = Some(parse_number("counter", &value)?);
+            }
+            _ => {}
+        }
+    }
+
+    if token.secret.is_empty() {
+        return Err(Error::InvalidOtpData("No secret found in URL".to_string()));
+    }
+
+    Ok(token)
 }
 
-fn parse_migration_url(url: &str) -> Result<Vec<TokenData>, String> {
+fn parse_number<T: std::str::FromStr<Err = std::num::ParseIntError>>(
+    field: &'static str,
+    value: &str,
+) -> Result<T> {
+    value.parse().map_err(|source| Error::InvalidNumber {
+        field,
+        value: value.to_string(),
+        source,
+    })
+}
+
+fn parse_migration_url(url: &str) -> Result<MigrationPayload> {
     use base64::Engine;
 
     // Parse the URL
-    let parsed_url =
-        url::Url::parse(url).map_err(|e| format!("Failed to parse migration URL: {}", e))?;
+    let parsed_url = url::Url::parse(url)?;
 
     // Get the data parameter
     let data_param = parsed_url
         .query_pairs()
         .find(|(key, _)| key == "data")
         .map(|(_, value)| value.to_string())
-        .ok_or_else(|| "No data parameter in migration URL".to_string())?;
+        .ok_or_else(|| Error::InvalidOtpData("No data parameter in migration URL".to_string()))?;
 
     // Decode the base64 data
-    let decoded = base64::engine::general_purpose::STANDARD
-        .decode(data_param.as_bytes())
-        .map_err(|e| format!("Failed to decode base64: {}", e))?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(data_param.as_bytes())?;
 
     // Parse the protobuf data
     parse_migration_payload(&decoded)
 }
 
-// Simple protobuf parser for Google Authenticator migration format
-fn parse_migration_payload(data: &[u8]) -> Result<Vec<TokenData>, String> {
-    let mut tokens = Vec::new();
-    let mut i = 0;
+/// A single protobuf field value, as far as the migration schema needs it
+enum FieldValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
 
-    while i < data.len() {
-        if data[i] == 0x0a {
-            // Field 1 (OtpParameters)
-            i += 1;
-            let (length, bytes_read) = decode_varint(&data[i..])?;
-            i += bytes_read;
+/// Read the field starting at `*pos` and advance past it.
+/// Returns the field number and its value.
+fn read_field<'a>(data: &'a [u8], pos: &mut usize) -> Result<(u64, FieldValue<'a>)> {
+    let (key, bytes_read) = decode_varint(&data[*pos..])?;
+    *pos += bytes_read;
 
-            if i + length > data.len() {
-                return Err("Invalid protobuf data".to_string());
+    let field_number = key >> 3;
+    let value = match key & 0x07 {
+        0 => {
+            let (value, bytes_read) = decode_varint(&data[*pos..])?;
+            *pos += bytes_read;
+            FieldValue::Varint(value)
+        }
+        1 | 5 => {
+            let size = if key & 0x07 == 1 { 8 } else { 4 };
+            if *pos + size > data.len() {
+                return Err(invalid_protobuf("field extends beyond buffer"));
             }
-
-            let param_data = &data[i..i + length];
-            if let Ok(token) = parse_otp_parameter(param_data) {
-                tokens.push(token);
+            *pos += size;
+            FieldValue::Fixed
+        }
+        2 => {
+            let (length, bytes_read) = decode_varint(&data[*pos..])?;
+            *pos += bytes_read;
+            let length =
+                usize::try_from(length).map_err(|_| invalid_protobuf("length too large"))?;
+            if length > data.len() - *pos {
+                return Err(invalid_protobuf("field extends beyond buffer"));
             }
-            i += length;
-        } else {
-            // Skip unknown fields
-            i += 1;
-            if i < data.len() {
-                let wire_type = data[i - 1] & 0x07;
-                match wire_type {
-                    0 => {
-                        // Varint
-                        let (_, bytes_read) = decode_varint(&data[i..])?;
-                        i += bytes_read;
-                    }
-                    2 => {
-                        // Length-delimited
-                        let (length, bytes_read) = decode_varint(&data[i..])?;
-                        if i + bytes_read + length > data.len() {
-                            return Err("Invalid protobuf data".to_string());
-                        }
-                        i += bytes_read + length;
-                    }
-                    _ => i += 1,
-                }
+            let bytes = &data[*pos..*pos + length];
+            *pos += length;
+            FieldValue::Bytes(bytes)
+        }
+        wire_type => {
+            return Err(invalid_protobuf(&format!(
+                "unsupported wire type {}",
+                wire_type
+            )))
+        }
+    };
+
+    Ok((field_number, value))
+}
+
+/// Position of a QR code within a multi-QR Google Authenticator export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchInfo {
+    pub id: i32,
+    pub index: i32,
+    pub size: i32,
+}
+
+/// Decoded `MigrationPayload` of an `otpauth-migration://` URL
+#[derive(Debug, Clone, Default)]
+pub struct MigrationPayload {
+    pub tokens: Vec<TokenData>,
+    pub version: i32,
+    pub batch_size: i32,
+    pub batch_index: i32,
+    pub batch_id: i32,
+    /// Entries that could not be imported, e.g. "entry 3: Token 'legacy' uses MD5"
+    pub skipped: Vec<String>,
+}
+
+impl MigrationPayload {
+    pub fn batch(&self) -> BatchInfo {
+        BatchInfo {
+            id: self.batch_id,
+            index: self.batch_index,
+            size: self.batch_size,
+        }
+    }
+}
+
+// Protobuf parser for the Google Authenticator migration format:
+//
+// message MigrationPayload {
+//   repeated OtpParameters otp_parameters = 1;
+//   int32 version = 2;
+//   int32 batch_size = 3;
+//   int32 batch_index = 4;
+//   int32 batch_id = 5;
+// }
+fn parse_migration_payload(data: &[u8]) -> Result<MigrationPayload> {
+    let mut payload = MigrationPayload::default();
+    let mut parameters = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        // int32 fields are sign-extended varints, truncating restores negative values
+        match read_field(data, &mut i)? {
+            (1, FieldValue::Bytes(param_data)) => parameters.push(param_data),
+            (2, FieldValue::Varint(value)) => payload.version = value as i32,
+            (3, FieldValue::Varint(value)) => payload.batch_size = value as i32,
+            (4, FieldValue::Varint(value)) => payload.batch_index = value as i32,
+            (5, FieldValue::Varint(value)) => payload.batch_id = value as i32,
+            _ => {} // Skip unknown fields
+        }
+    }
+
+    let batch = payload.batch();
+    for (index, param_data) in parameters.into_iter().enumerate() {
+        match parse_otp_parameter(param_data) {
+            Ok(mut token) => {
+                token.batch = Some(batch);
+                payload.tokens.push(token);
             }
+            Err(e) => payload.skipped.push(format!("entry {}: {}", index + 1, e)),
         }
     }
 
-    Ok(tokens)
+    Ok(payload)
 }
 
-fn parse_otp_parameter(data: &[u8]) -> Result<TokenData, String> {
+// message OtpParameters {
+//   bytes secret = 1;
+//   string name = 2;
+//   string issuer = 3;
+//   Algorithm algorithm = 4;   // 0: unspecified, 1: SHA1, 2: SHA256, 3: SHA512, 4: MD5
+//   DigitCount digits = 5;     // 0: unspecified, 1: six, 2: eight
+//   OtpType type = 6;          // 0: unspecified, 1: HOTP, 2: TOTP
+//   int64 counter = 7;
+// }
+fn parse_otp_parameter(data: &[u8]) -> Result<TokenData> {
     let mut secret_bytes = None;
     let mut name = None;
+    let mut issuer = None;
+    let mut algorithm = 0;
+    let mut digits = 0;
+    let mut otp_type = 0;
+    let mut counter = 0;
     let mut i = 0;
 
     while i < data.len() {
-        let field = data[i];
-        i += 1;
-
-        match field {
-            0x0a => {
-                // Field 1: secret (bytes)
-                let (length, bytes_read) = decode_varint(&data[i..])?;
-                i += bytes_read;
-                if i + length > data.len() {
-                    return Err("Invalid protobuf data: field extends beyond buffer".to_string());
-                }
-                secret_bytes = Some(data[i..i + length].to_vec());
-                i += length;
-            }
-            0x12 => {
-                // Field 2: name (string)
-                let (length, bytes_read) = decode_varint(&data[i..])?;
-                i += bytes_read;
-                if i + length > data.len() {
-                    return Err("Invalid protobuf data: field extends beyond buffer".to_string());
-                }
-                name = Some(String::from_utf8_lossy(&data[i..i + length]).to_string());
-                i += length;
-            }
-            _ => {
-                // Skip unknown field
-                let wire_type = field & 0x07;
-                match wire_type {
-                    0 => {
-                        // Varint
-                        let (_, bytes_read) = decode_varint(&data[i..])?;
-                        i += bytes_read;
-                    }
-                    2 => {
-                        // Length-delimited
-                        if i < data.len() {
-                            let (length, bytes_read) = decode_varint(&data[i..])?;
-                            if i + bytes_read + length > data.len() {
-                                return Err("Invalid protobuf data: field extends beyond buffer"
-                                    .to_string());
-                            }
-                            i += bytes_read + length;
-                        }
-                    }
-                    _ => {
-                        if i < data.len() {
-                            i += 1;
-                        }
-                    }
-                }
+        match read_field(data, &mut i)? {
+            (1, FieldValue::Bytes(bytes)) => secret_bytes = Some(bytes.to_vec()),
+            (2, FieldValue::Bytes(bytes)) => {
+                name = Some(String::from_utf8_lossy(bytes).to_string());
             }
+            (3, FieldValue::Bytes(bytes)) => {
+                issuer = Some(String::from_utf8_lossy(bytes).to_string());
+            }
+            (4, FieldValue::Varint(value)) => algorithm = value,
+            (5, FieldValue::Varint(value)) => digits = value,
+            (6, FieldValue::Varint(value)) => otp_type = value,
+            (7, FieldValue::Varint(value)) => counter = value,
+            _ => {} // Skip unknown fields
         }
     }
 
-    let secret_bytes = secret_bytes.ok_or_else(|| "No secret found".to_string())?;
+    let secret_bytes =
+        secret_bytes.ok_or_else(|| Error::InvalidOtpData("No secret found".to_string()))?;
     let secret = BASE32_NOPAD.encode(&secret_bytes);
-    let name = name.unwrap_or_else(|| "Unknown".to_string());
 
-    Ok(TokenData { name, secret })
+    // Google Authenticator may store the name as "Issuer:Account"
+    let (label_issuer, name) = split_label(&name.unwrap_or_default());
+    let issuer = issuer.filter(|issuer| !issuer.is_empty()).or(label_issuer);
+    let name = if name.is_empty() && issuer.is_none() {
+        "Unknown".to_string()
+    } else {
+        name
+    };
+
+    let algorithm = match algorithm {
+        0 => None,
+        1 => Some(Algorithm::Sha1),
+        2 => Some(Algorithm::Sha256),
+        3 => Some(Algorithm::Sha512),
+        4 => return Err(unsupported_token(&name, "uses unsupported algorithm MD5")),
+        other => {
+            return Err(unsupported_token(
+                &name,
+                &format!("uses unknown algorithm {}", other),
+            ))
+        }
+    };
+
+    let digits = match digits {
+        0 => None,
+        1 => Some(6),
+        2 => Some(8),
+        other => {
+            return Err(unsupported_token(
+                &name,
+                &format!("uses unknown digit count {}", other),
+            ))
+        }
+    };
+
+    let token_type = match otp_type {
+        1 => TokenType::Hotp,
+        0 | 2 => TokenType::Totp,
+        other => {
+            return Err(unsupported_token(
+                &name,
+                &format!("uses unknown OTP type {}", other),
+            ))
+        }
+    };
+
+    Ok(TokenData {
+        name,
+        secret,
+        issuer,
+        token_type,
+        algorithm,
+        digits,
+        // Google Authenticator only supports 30 second periods
+        period: None,
+        counter: (token_type == TokenType::Hotp).then_some(counter),
+        batch: None,
+    })
 }
 
-fn decode_varint(data: &[u8]) -> Result<(usize, usize), String> {
-    let mut result = 0usize;
+fn decode_varint(data: &[u8]) -> Result<(u64, usize)> {
+    let mut result = 0u64;
     let mut shift = 0;
     let mut i = 0;
 
@@ -220,7 +774,7 @@ fn decode_varint(data: &[u8]) -> Result<(usize, usize), String> {
         let byte = data[i];
         i += 1;
 
-        result |= ((byte & 0x7f) as usize) << shift;
+        result |= ((byte & 0x7f) as u64) << shift;
 
         if byte & 0x80 == 0 {
             return Ok((result, i));
@@ -229,9 +783,992 @@ fn decode_varint(data: &[u8]) -> Result<(usize, usize), String> {
         shift += 7;
 
         if shift > 63 {
-            return Err("Varint too long".to_string());
+            return Err(invalid_protobuf("varint too long"));
         }
     }
 
-    Err("Incomplete varint".to_string())
+    Err(invalid_protobuf("incomplete varint"))
+}
+
+fn invalid_protobuf(reason: &str) -> Error {
+    Error::InvalidOtpData(format!("Invalid protobuf data: {}", reason))
+}
+
+fn unsupported_token(name: &str, reason: &str) -> Error {
+    Error::InvalidToken {
+        name: name.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Number of tokens per QR code in an export, about what Google Authenticator uses
+pub const MIGRATION_BATCH_SIZE: usize = 10;
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn write_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    write_varint(buf, field << 3);
+    write_varint(buf, value);
+}
+
+fn write_bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    write_varint(buf, (field << 3) | 2);
+    write_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+/// Check that a token can be represented in a Google Authenticator export
+pub fn check_migration_support(token: &TokenData) -> Result<()> {
+    if !matches!(token.digits, None | Some(6) | Some(8)) {
+        return Err(unsupported_token(
+            &token.display_name(),
+            &format!(
+                "uses {} digits, only 6 or 8 can be exported",
+                token.digits.unwrap_or_default()
+            ),
+        ));
+    }
+    if token.period.is_some_and(|period| period != DEFAULT_PERIOD) {
+        return Err(unsupported_token(
+            &token.display_name(),
+            &format!(
+                "uses a {} second period, only {} seconds can be exported",
+                token.period.unwrap_or_default(),
+                DEFAULT_PERIOD
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn encode_otp_parameter(token: &TokenData) -> Result<Vec<u8>> {
+    check_migration_support(token)?;
+    let secret_bytes = decode_secret(&token.secret)?;
+
+    let mut buf = Vec::new();
+    write_bytes_field(&mut buf, 1, &secret_bytes);
+    write_bytes_field(&mut buf, 2, token.name.as_bytes());
+    if let Some(issuer) = &token.issuer {
+        write_bytes_field(&mut buf, 3, issuer.as_bytes());
+    }
+
+    let algorithm = match token.algorithm.unwrap_or_default() {
+        Algorithm::Sha1 => 1,
+        Algorithm::Sha256 => 2,
+        Algorithm::Sha512 => 3,
+    };
+    write_varint_field(&mut buf, 4, algorithm);
+    write_varint_field(&mut buf, 5, if token.digits == Some(8) { 2 } else { 1 });
+
+    match token.token_type {
+        TokenType::Hotp => {
+            write_varint_field(&mut buf, 6, 1);
+            write_varint_field(&mut buf, 7, token.counter.unwrap_or(0));
+        }
+        TokenType::Totp => write_varint_field(&mut buf, 6, 2),
+    }
+
+    Ok(buf)
+}
+
+/// Encode a `MigrationPayload`, the inverse of `parse_migration_payload`
+pub fn encode_migration_payload(payload: &MigrationPayload) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    for token in &payload.tokens {
+        write_bytes_field(&mut buf, 1, &encode_otp_parameter(token)?);
+    }
+
+    // int32 fields are sign-extended to 64 bits on the wire
+    write_varint_field(&mut buf, 2, payload.version as i64 as u64);
+    write_varint_field(&mut buf, 3, payload.batch_size as i64 as u64);
+    write_varint_field(&mut buf, 4, payload.batch_index as i64 as u64);
+    write_varint_field(&mut buf, 5, payload.batch_id as i64 as u64);
+
+    Ok(buf)
+}
+
+/// Build `otpauth-migration://` URLs for the tokens, `batch_size` tokens per URL
+pub fn build_migration_urls(tokens: &[TokenData], batch_size: usize) -> Result<Vec<String>> {
+    use base64::Engine;
+
+    if tokens.is_empty() {
+        return Err(Error::NoTokensToExport);
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    // Only has to tell exports apart, like the random id Google Authenticator uses
+    let batch_id = (now.as_secs() as u32 ^ now.subsec_nanos()) as i32;
+
+    let chunks: Vec<&[TokenData]> = tokens.chunks(batch_size.max(1)).collect();
+    let batch_count = chunks.len() as i32;
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let payload = MigrationPayload {
+                tokens: chunk.to_vec(),
+                version: 1,
+                batch_size: batch_count,
+                batch_index: index as i32,
+                batch_id,
+                ..Default::default()
+            };
+            let data = base64::engine::general_purpose::STANDARD
+                .encode(encode_migration_payload(&payload)?);
+
+            Ok(format!(
+                "otpauth-migration://offline?data={}",
+                urlencoding::encode(&data)
+            ))
+        })
+        .collect()
+}
+
+/// Build an `otpauth://` Key URI for a single token, the inverse of `parse_otpauth_url`
+pub fn build_otpauth_url(token: &TokenData) -> String {
+    let label = match &token.issuer {
+        Some(issuer) if !token.name.is_empty() => format!(
+            "{}:{}",
+            urlencoding::encode(issuer),
+            urlencoding::encode(&token.name)
+        ),
+        Some(issuer) => urlencoding::encode(issuer).to_string(),
+        None => urlencoding::encode(&token.name).to_string(),
+    };
+
+    let (token_type, moving_factor) = match token.token_type {
+        TokenType::Totp => (
+            "totp",
+            format!("period={}", token.period.unwrap_or(DEFAULT_PERIOD)),
+        ),
+        TokenType::Hotp => ("hotp", format!("counter={}", token.counter.unwrap_or(0))),
+    };
+    let algorithm = match token.algorithm.unwrap_or_default() {
+        Algorithm::Sha1 => "SHA1",
+        Algorithm::Sha256 => "SHA256",
+        Algorithm::Sha512 => "SHA512",
+    };
+
+    let mut url = format!(
+        "otpauth://{}/{}?secret={}",
+        token_type,
+        label,
+        token.secret.to_uppercase()
+    );
+    if let Some(issuer) = &token.issuer {
+        url.push_str(&format!("&issuer={}", urlencoding::encode(issuer)));
+    }
+    url.push_str(&format!(
+        "&algorithm={}&digits={}&{}",
+        algorithm,
+        token.digits.unwrap_or(DEFAULT_DIGITS),
+        moving_factor
+    ));
+
+    url
+}
+
+/// Render `content` as a QR code and save it as a PNG image
+pub fn write_qr_png(content: &str, path: &Path) -> Result<()> {
+    let code = qrcode::QrCode::new(content.as_bytes())?;
+
+    let image = code
+        .render::<image::Luma<u8>>()
+        .min_dimensions(400, 400)
+        .build();
+
+    image.save(path).map_err(|source| Error::Image {
+        action: "write",
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// Result of exporting tokens as Google Authenticator QR codes
+#[derive(Debug, Default)]
+pub struct MigrationExport {
+    pub files: Vec<PathBuf>,
+    /// Tokens left out because Google Authenticator cannot represent them or
+    /// their secret could not be resolved
+    pub skipped: Vec<String>,
+}
+
+/// Export tokens as `otpauth-migration://` QR code images into `dir`
+pub fn export_migration_qr_codes(tokens: &[TokenData], dir: &Path) -> Result<MigrationExport> {
+    let mut export = MigrationExport::default();
+
+    let supported: Vec<TokenData> = tokens
+        .iter()
+        .filter(|token| match check_migration_support(token) {
+            Ok(()) => true,
+            Err(e) => {
+                export.skipped.push(e.to_string());
+                false
+            }
+        })
+        .cloned()
+        .collect();
+
+    let urls = build_migration_urls(&supported, MIGRATION_BATCH_SIZE)?;
+    for (index, url) in urls.iter().enumerate() {
+        let path = dir.join(format!(
+            "otp-bar-export-{}-of-{}.png",
+            index + 1,
+            urls.len()
+        ));
+        write_qr_png(url, &path)?;
+        export.files.push(path);
+    }
+
+    Ok(export)
+}
+
+/// Export the tokens of `config` named in `names`, resolving their secrets with
+/// `resolve`. A token whose secret cannot be resolved is skipped, like the ones
+/// Google Authenticator cannot represent.
+pub fn export_config_tokens<E: std::fmt::Display>(
+    config: &Config,
+    names: &[String],
+    dir: &Path,
+    mut resolve: impl FnMut(&str, &config::TokenData) -> std::result::Result<config::TokenData, E>,
+) -> Result<MigrationExport> {
+    let mut tokens = Vec::new();
+    let mut unresolved = Vec::new();
+    for name in names {
+        let token = config
+            .get_token_data(name)
+            .ok_or_else(|| Error::TokenNotFound(name.clone()))?;
+        match resolve(name, token) {
+            Ok(token) => tokens.push(TokenData::from_config(name, &token)),
+            Err(e) => unresolved.push(unsupported_token(
+                name,
+                &format!("has a secret that could not be resolved: {}", e),
+            )),
+        }
+    }
+
+    let mut export = export_migration_qr_codes(&tokens, dir)?;
+    export
+        .skipped
+        .splice(0..0, unresolved.iter().map(Error::to_string));
+    Ok(export)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_otpauth_url_minimal() {
+        let token = parse_otpauth_url("otpauth://totp/alice?secret=JBSWY3DPEHPK3PXP").unwrap();
+
+        assert_eq!(token.name, "alice");
+        assert_eq!(token.secret, "JBSWY3DPEHPK3PXP");
+        assert_eq!(token.issuer, None);
+        assert_eq!(token.token_type, TokenType::Totp);
+        assert_eq!(token.algorithm, None);
+        assert_eq!(token.digits, None);
+        assert_eq!(token.period, None);
+    }
+
+    #[test]
+    fn test_parse_otpauth_url_all_parameters() {
+        let token = parse_otpauth_url(
+            "otpauth://totp/ACME%20Co:john.doe@email.com?secret=HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ\
+             &issuer=ACME%20Co&algorithm=SHA256&digits=8&period=60",
+        )
+        .unwrap();
+
+        assert_eq!(token.name, "john.doe@email.com");
+        assert_eq!(token.issuer.as_deref(), Some("ACME Co"));
+        assert_eq!(token.secret, "HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ");
+        assert_eq!(token.algorithm, Some(Algorithm::Sha256));
+        assert_eq!(token.digits, Some(8));
+        assert_eq!(token.period, Some(60));
+        assert_eq!(token.display_name(), "ACME Co (john.doe@email.com)");
+    }
+
+    #[test]
+    fn test_parse_otpauth_url_issuer_from_label() {
+        let token =
+            parse_otpauth_url("otpauth://totp/Example:%20alice?secret=jbswy3dpehpk3pxp%3D%3D")
+                .unwrap();
+
+        assert_eq!(token.name, "alice");
+        assert_eq!(token.issuer.as_deref(), Some("Example"));
+        assert_eq!(token.secret, "JBSWY3DPEHPK3PXP");
+    }
+
+    #[test]
+    fn test_parse_otpauth_url_hotp() {
+        let token =
+            parse_otpauth_url("otpauth://hotp/VPN?secret=JBSWY3DPEHPK3PXP&counter=42").unwrap();
+
+        assert_eq!(token.token_type, TokenType::Hotp);
+        assert_eq!(token.counter, Some(42));
+
+        let config_token = token.to_config_token();
+        assert_eq!(config_token.token_type, TokenType::Hotp);
+        assert_eq!(config_token.counter, Some(42));
+    }
+
+    #[test]
+    fn test_parse_otpauth_url_invalid() {
+        assert!(matches!(
+            parse_otpauth_url("otpauth://totp/alice"),
+            Err(Error::InvalidOtpData(_))
+        ));
+        assert!(parse_otpauth_url("otpauth://xotp/alice?secret=JBSWY3DPEHPK3PXP").is_err());
+        assert!(matches!(
+            parse_otpauth_url("otpauth://totp/alice?secret=JBSWY3DPEHPK3PXP&algorithm=MD5"),
+            Err(Error::InvalidParameter(_))
+        ));
+        let err = parse_otpauth_url("otpauth://totp/alice?secret=JBSWY3DPEHPK3PXP&digits=six")
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidNumber {
+                field: "digits",
+                ..
+            }
+        ));
+        assert!(std::error::Error::source(&err).is_some());
+
+        // The decoding error stays available to callers
+        let err = parse_migration_url("otpauth-migration://offline?data=not*base64").unwrap_err();
+        assert!(matches!(err, Error::Base64(_)));
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn test_to_config_token() {
+        let token = TokenData {
+            name: "alice".to_string(),
+            secret: "JBSWY3DPEHPK3PXP".to_string(),
+            issuer: Some("Example".to_string()),
+            algorithm: Some(Algorithm::Sha512),
+            digits: Some(8),
+            period: Some(60),
+            ..Default::default()
+        };
+
+        let config_token = token.to_config_token();
+        assert_eq!(config_token.secret, "JBSWY3DPEHPK3PXP");
+        assert_eq!(config_token.issuer.as_deref(), Some("Example"));
+        assert_eq!(config_token.algorithm, Some(Algorithm::Sha512));
+        assert_eq!(config_token.digits, Some(8));
+        assert_eq!(config_token.period, Some(60));
+        assert_eq!(config_token.counter, None);
+    }
+
+    fn varint_field(field: u64, value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_varint_field(&mut bytes, field, value);
+        bytes
+    }
+
+    fn bytes_field(field: u64, value: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_bytes_field(&mut bytes, field, value);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_migration_payload() {
+        let mut totp = bytes_field(1, b"12345678901234567890");
+        totp.extend(bytes_field(2, b"alice@example.com"));
+        totp.extend(bytes_field(3, b"Example"));
+        totp.extend(varint_field(4, 2)); // SHA256
+        totp.extend(varint_field(5, 2)); // eight digits
+        totp.extend(varint_field(6, 2)); // TOTP
+
+        let mut hotp = bytes_field(1, b"abcdefghij");
+        hotp.extend(bytes_field(2, b"VPN:bob"));
+        hotp.extend(varint_field(6, 1)); // HOTP
+        hotp.extend(varint_field(7, 5));
+
+        let mut payload = bytes_field(1, &totp);
+        payload.extend(bytes_field(1, &hotp));
+        payload.extend(varint_field(2, 1));
+        payload.extend(varint_field(3, 2));
+        payload.extend(varint_field(4, 1));
+        // Negative int32 values are encoded as ten byte varints
+        payload.extend(varint_field(5, -12345i64 as u64));
+
+        let parsed = parse_migration_payload(&payload).unwrap();
+        assert_eq!(parsed.version, 1);
+        assert_eq!(
+            parsed.batch(),
+            BatchInfo {
+                id: -12345,
+                index: 1,
+                size: 2
+            }
+        );
+        assert_eq!(parsed.tokens.len(), 2);
+
+        let totp = &parsed.tokens[0];
+        assert_eq!(totp.name, "alice@example.com");
+        assert_eq!(totp.issuer.as_deref(), Some("Example"));
+        assert_eq!(totp.secret, BASE32_NOPAD.encode(b"12345678901234567890"));
+        assert_eq!(totp.algorithm, Some(Algorithm::Sha256));
+        assert_eq!(totp.digits, Some(8));
+        assert_eq!(totp.token_type, TokenType::Totp);
+        assert_eq!(totp.counter, None);
+        assert_eq!(totp.batch, Some(parsed.batch()));
+
+        let hotp = &parsed.tokens[1];
+        assert_eq!(hotp.name, "bob");
+        assert_eq!(hotp.issuer.as_deref(), Some("VPN"));
+        assert_eq!(hotp.algorithm, None);
+        assert_eq!(hotp.digits, None);
+        assert_eq!(hotp.token_type, TokenType::Hotp);
+        assert_eq!(hotp.counter, Some(5));
+    }
+
+    #[test]
+    fn test_parse_migration_payload_skips_unsupported_entries() {
+        let mut md5 = bytes_field(1, b"12345678901234567890");
+        md5.extend(bytes_field(2, b"legacy"));
+        md5.extend(varint_field(4, 4)); // MD5
+
+        let no_secret = bytes_field(2, b"broken");
+
+        let mut unnamed = bytes_field(1, b"12345678901234567890");
+        // Unknown fields are ignored
+        unnamed.extend(bytes_field(15, b"ignored"));
+
+        let mut payload = bytes_field(1, &md5);
+        payload.extend(bytes_field(1, &no_secret));
+        payload.extend(bytes_field(1, &unnamed));
+
+        let parsed = parse_migration_payload(&payload).unwrap();
+        assert_eq!(parsed.tokens.len(), 1);
+        assert_eq!(parsed.tokens[0].name, "Unknown");
+        assert_eq!(parsed.skipped.len(), 2);
+        assert!(parsed.skipped[0].starts_with("entry 1: Token 'legacy'"));
+        assert!(parsed.skipped[1].starts_with("entry 2: "));
+    }
+
+    #[test]
+    fn test_parse_migration_payload_truncated() {
+        let mut payload = bytes_field(1, b"12345678901234567890");
+        payload.truncate(payload.len() - 3);
+
+        assert!(matches!(
+            parse_migration_payload(&payload),
+            Err(Error::InvalidOtpData(_))
+        ));
+    }
+
+    fn batch_token(name: &str, id: i32, index: i32, size: i32) -> TokenData {
+        TokenData {
+            name: name.to_string(),
+            secret: "JBSWY3DPEHPK3PXP".to_string(),
+            batch: Some(BatchInfo { id, index, size }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_missing_batch_indexes() {
+        let tokens = vec![
+            batch_token("a", 7, 0, 3),
+            batch_token("b", 7, 0, 3),
+            batch_token("c", 7, 2, 3),
+            batch_token("d", -1, 0, 1),
+            TokenData {
+                name: "single".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(missing_batch_indexes(&tokens), vec![(7, 3, vec![1])]);
+    }
+
+    #[test]
+    fn test_missing_batch_indexes_complete() {
+        let tokens = vec![batch_token("a", 7, 1, 2), batch_token("b", 7, 0, 2)];
+
+        assert!(missing_batch_indexes(&tokens).is_empty());
+    }
+
+    #[test]
+    fn test_plan_import() {
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        let mut config = Config::default();
+        config.add_token("GitHub".to_string(), secret.to_string());
+
+        let import = BatchImport {
+            tokens: vec![
+                TokenData {
+                    name: "GitHub".to_string(),
+                    secret: secret.to_string(),
+                    ..Default::default()
+                },
+                TokenData {
+                    name: "alice".to_string(),
+                    issuer: Some("GitLab".to_string()),
+                    secret: "JBSWY3DPEHPK3PXP".to_string(),
+                    ..Default::default()
+                },
+                TokenData {
+                    name: "vpn".to_string(),
+                    secret: secret.to_string(),
+                    token_type: TokenType::Hotp,
+                    counter: Some(1),
+                    ..Default::default()
+                },
+            ],
+            warnings: vec!["broken.png: No QR code found".to_string()],
+        };
+
+        let mut plan = plan_import(import, &config, ImportPolicy::Rename, 59);
+        assert_eq!(plan.warnings.len(), 1);
+        assert_eq!(
+            plan.tokens[0].import.outcome,
+            ImportOutcome::Duplicate {
+                existing: "GitHub".to_string()
+            }
+        );
+        assert!(!plan.tokens[0].selected);
+        assert!(!plan.tokens[0].can_import());
+        assert!(plan.tokens[1].can_import());
+        assert_eq!(plan.tokens[1].import.name, "GitLab (alice)");
+        assert_eq!(plan.tokens[1].import.outcome, ImportOutcome::Added);
+        // RFC 6238 at 59 seconds and RFC 4226 with counter 1
+        assert_eq!(plan.tokens[0].code.as_deref(), Ok("287082"));
+        assert_eq!(plan.tokens[2].code.as_deref(), Ok("287082"));
+
+        // Planning does not touch the config
+        assert_eq!(config.list_token_names(), vec!["GitHub"]);
+
+        assert_eq!(plan.selected_count(), 2);
+        plan.tokens[1].selected = false;
+        let selected = plan.selected_tokens();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].name, "vpn");
+
+        plan.refresh_codes(1111111109);
+        assert_eq!(plan.tokens[0].code.as_deref(), Ok("081804"));
+        assert_eq!(plan.tokens[2].code.as_deref(), Ok("287082"));
+    }
+
+    #[test]
+    fn test_image_files_in_dir() {
+        let dir = std::env::temp_dir().join("otp_bar_test_image_files_in_dir");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested.png")).unwrap();
+        for name in ["b.PNG", "a.jpg", "notes.txt", "c.jpeg", "d.webp", "kit.pdf"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let names: Vec<String> = image_files_in_dir(&dir)
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names, vec!["a.jpg", "b.PNG", "c.jpeg", "d.webp", "kit.pdf"]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_difficult_images() {
+        // Each image holds `otpauth://totp/Corpus:<file stem>`, see tests/qr-corpus/README.md
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/qr-corpus");
+        let images = image_files_in_dir(&corpus).unwrap();
+        assert!(!images.is_empty());
+
+        for image in images {
+            let stem = image.file_stem().unwrap().to_string_lossy().to_string();
+            let luma = image::open(&image).unwrap().to_luma8();
+
+            // Only the preprocessed variants decode
+            let mut prepared = rqrr::PreparedImage::prepare(luma.clone());
+            assert!(
+                prepared
+                    .detect_grids()
+                    .iter()
+                    .all(|grid| grid.decode().is_err()),
+                "{} decodes without preprocessing",
+                stem
+            );
+            let codes = preprocess::decode_qr_codes(&luma).unwrap();
+            assert!(codes.iter().all(|code| code.is_ok()), "{}", stem);
+
+            let tokens = parse_qr_and_extract_tokens(&image.to_string_lossy())
+                .unwrap_or_else(|e| panic!("{}: {}", image.display(), e))
+                .tokens;
+            assert_eq!(tokens.len(), 1, "{}", stem);
+            assert_eq!(tokens[0].name, stem);
+            assert_eq!(tokens[0].issuer.as_deref(), Some("Corpus"));
+        }
+    }
+
+    #[test]
+    fn test_parse_image_with_a_code_that_is_not_otp() {
+        let path = std::env::temp_dir().join("otp_bar_test_partial_import.png");
+        let render = |content: &str| {
+            qrcode::QrCode::new(content.as_bytes())
+                .unwrap()
+                .render::<image::Luma<u8>>()
+                .min_dimensions(300, 300)
+                .build()
+        };
+        let token = render("otpauth://totp/Example:alice?secret=JBSWY3DPEHPK3PXP&issuer=Example");
+        let website = render("https://example.com");
+
+        // Both codes next to each other on a white background
+        let mut image = image::GrayImage::from_pixel(
+            token.width() + website.width(),
+            token.height().max(website.height()),
+            image::Luma([255]),
+        );
+        image::imageops::overlay(&mut image, &token, 0, 0);
+        image::imageops::overlay(&mut image, &website, token.width() as i64, 0);
+        image.save(&path).unwrap();
+
+        let import = parse_qr_and_extract_tokens(&path.to_string_lossy()).unwrap();
+        assert_eq!(import.tokens.len(), 1);
+        assert_eq!(import.tokens[0].name, "alice");
+        assert_eq!(import.failures.len(), 1);
+        assert!(import.failures[0].ends_with(": QR code does not contain OTP data"));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_parse_other_image_formats() {
+        let code = qrcode::QrCode::new(
+            b"otpauth://totp/Example:alice?secret=JBSWY3DPEHPK3PXP&issuer=Example",
+        )
+        .unwrap()
+        .render::<image::Luma<u8>>()
+        .min_dimensions(300, 300)
+        .build();
+        // Not every encoder takes grayscale, e.g. GIF
+        let code = image::DynamicImage::ImageLuma8(code).to_rgb8();
+
+        for extension in ["webp", "gif", "bmp", "tiff"] {
+            let path = std::env::temp_dir().join(format!("otp_bar_test_format.{}", extension));
+            code.save(&path).unwrap();
+
+            let import = parse_qr_and_extract_tokens(&path.to_string_lossy())
+                .unwrap_or_else(|e| panic!("{}: {}", extension, e));
+            assert_eq!(import.tokens.len(), 1, "{}", extension);
+            assert_eq!(import.tokens[0].name, "alice");
+
+            let _ = fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn test_parse_pdf() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/pdf/recovery-kit.pdf");
+
+        let import = parse_qr_and_extract_tokens(&path.to_string_lossy()).unwrap();
+        let names: Vec<String> = import.tokens.iter().map(TokenData::display_name).collect();
+        assert_eq!(names, vec!["Kit (flate)", "Kit (jpeg)", "Kit (mask)"]);
+        assert_eq!(import.tokens[2].token_type, TokenType::Hotp);
+        assert_eq!(import.tokens[2].counter, Some(3));
+        assert!(import.failures.is_empty());
+    }
+
+    #[test]
+    fn test_parse_pdf_without_images() {
+        // The QR code on this page is drawn with rectangles, not embedded as an image
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/pdf/vector-qr.pdf");
+
+        assert!(matches!(
+            parse_qr_and_extract_tokens(&path.to_string_lossy()),
+            Err(Error::NoQrCodeInPdf)
+        ));
+    }
+
+    #[test]
+    fn test_parse_qr_images_reports_unreadable_files() {
+        let missing = std::env::temp_dir().join("otp_bar_test_missing_image.png");
+        let _ = fs::remove_file(&missing);
+
+        let err = parse_qr_images(&[missing]).unwrap_err();
+        assert!(err.to_string().contains("otp_bar_test_missing_image.png"));
+    }
+
+    #[test]
+    fn test_parse_qr_images_keeps_unbatched_exports() {
+        use base64::Engine;
+
+        let dir = std::env::temp_dir().join("otp_bar_test_unbatched_exports");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // Two single exports without batch fields, which both decode as batch 0, index 0
+        let paths: Vec<PathBuf> = ["alice", "bob"]
+            .iter()
+            .map(|name| {
+                let payload = MigrationPayload {
+                    tokens: vec![TokenData {
+                        name: name.to_string(),
+                        secret: BASE32_NOPAD.encode(name.as_bytes()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                };
+                let data = base64::engine::general_purpose::STANDARD
+                    .encode(encode_migration_payload(&payload).unwrap());
+                let url = format!(
+                    "otpauth-migration://offline?data={}",
+                    urlencoding::encode(&data)
+                );
+                let path = dir.join(format!("{}.png", name));
+                write_qr_png(&url, &path).unwrap();
+                path
+            })
+            .collect();
+
+        let import = parse_qr_images(&paths).unwrap();
+        let names: Vec<&str> = import
+            .tokens
+            .iter()
+            .map(|token| token.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["alice", "bob"]);
+        assert!(import.warnings.is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_from_config_round_trip() {
+        let token = TokenData {
+            name: "alice".to_string(),
+            secret: "JBSWY3DPEHPK3PXP".to_string(),
+            issuer: Some("Example".to_string()),
+            ..Default::default()
+        };
+
+        let restored = TokenData::from_config(&token.display_name(), &token.to_config_token());
+        assert_eq!(restored, token);
+
+        let issuer_only = config::TokenData {
+            issuer: Some("Example".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(TokenData::from_config("Example", &issuer_only).name, "");
+    }
+
+    #[test]
+    fn test_encode_migration_payload_round_trip() {
+        let payload = MigrationPayload {
+            tokens: vec![
+                TokenData {
+                    name: "alice@example.com".to_string(),
+                    secret: BASE32_NOPAD.encode(b"12345678901234567890"),
+                    issuer: Some("Example".to_string()),
+                    algorithm: Some(Algorithm::Sha512),
+                    digits: Some(8),
+                    ..Default::default()
+                },
+                TokenData {
+                    name: "vpn".to_string(),
+                    secret: "JBSWY3DPEHPK3PXP".to_string(),
+                    token_type: TokenType::Hotp,
+                    counter: Some(3),
+                    ..Default::default()
+                },
+            ],
+            version: 1,
+            batch_size: 3,
+            batch_index: 2,
+            batch_id: -42,
+            ..Default::default()
+        };
+
+        let decoded =
+            parse_migration_payload(&encode_migration_payload(&payload).unwrap()).unwrap();
+        assert_eq!(decoded.batch(), payload.batch());
+        assert_eq!(decoded.version, 1);
+        assert_eq!(decoded.tokens.len(), 2);
+
+        let alice = &decoded.tokens[0];
+        assert_eq!(alice.name, "alice@example.com");
+        assert_eq!(alice.issuer.as_deref(), Some("Example"));
+        assert_eq!(alice.secret, payload.tokens[0].secret);
+        assert_eq!(alice.algorithm, Some(Algorithm::Sha512));
+        assert_eq!(alice.digits, Some(8));
+
+        let vpn = &decoded.tokens[1];
+        assert_eq!(vpn.token_type, TokenType::Hotp);
+        assert_eq!(vpn.counter, Some(3));
+    }
+
+    #[test]
+    fn test_check_migration_support() {
+        let token = TokenData {
+            name: "slow".to_string(),
+            secret: "JBSWY3DPEHPK3PXP".to_string(),
+            period: Some(60),
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_migration_support(&token),
+            Err(Error::InvalidToken { .. })
+        ));
+
+        let token = TokenData {
+            digits: Some(7),
+            period: None,
+            ..token
+        };
+        assert!(check_migration_support(&token).is_err());
+    }
+
+    #[test]
+    fn test_export_migration_qr_codes_round_trip() {
+        let dir = std::env::temp_dir().join("otp_bar_test_export_migration");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut tokens: Vec<TokenData> = (0..MIGRATION_BATCH_SIZE + 2)
+            .map(|i| TokenData {
+                name: format!("user{}", i),
+                secret: BASE32_NOPAD.encode(format!("secret-number-{:02}", i).as_bytes()),
+                issuer: Some("Example".to_string()),
+                ..Default::default()
+            })
+            .collect();
+        tokens.push(TokenData {
+            name: "slow".to_string(),
+            secret: "JBSWY3DPEHPK3PXP".to_string(),
+            period: Some(60),
+            ..Default::default()
+        });
+
+        let export = export_migration_qr_codes(&tokens, &dir).unwrap();
+        assert_eq!(export.files.len(), 2);
+        assert_eq!(export.skipped.len(), 1);
+
+        let mut imported = Vec::new();
+        for file in &export.files {
+            imported.extend(
+                parse_qr_and_extract_tokens(&file.to_string_lossy())
+                    .unwrap()
+                    .tokens,
+            );
+        }
+        assert!(missing_batch_indexes(&imported).is_empty());
+
+        let expected = &tokens[..MIGRATION_BATCH_SIZE + 2];
+        assert_eq!(imported.len(), expected.len());
+        for (imported, expected) in imported.iter().zip(expected) {
+            assert_eq!(imported.name, expected.name);
+            assert_eq!(imported.issuer, expected.issuer);
+            assert_eq!(imported.secret, expected.secret);
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_export_config_tokens() {
+        let dir = std::env::temp_dir().join("otp_bar_test_export_config_tokens");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut config = Config::default();
+        for name in ["GitHub", "GitLab"] {
+            config.add_token(name.to_string(), "JBSWY3DPEHPK3PXP".to_string());
+        }
+        config.tokens.insert(
+            "VPN".to_string(),
+            config::TokenData {
+                secret_env: Some("OTP_BAR_TEST_UNSET_SECRET".to_string()),
+                ..Default::default()
+            },
+        );
+        let resolve = |_: &str, token: &config::TokenData| {
+            if token.secret_env.is_some() {
+                Err("OTP_BAR_TEST_UNSET_SECRET is not set")
+            } else {
+                Ok(token.clone())
+            }
+        };
+
+        // Only the chosen tokens, the one without a secret is reported
+        let names = vec!["GitLab".to_string(), "VPN".to_string()];
+        let export = export_config_tokens(&config, &names, &dir, resolve).unwrap();
+        assert_eq!(export.files.len(), 1);
+        assert_eq!(export.skipped.len(), 1);
+        assert!(export.skipped[0].contains("VPN"));
+
+        let imported = parse_qr_and_extract_tokens(&export.files[0].to_string_lossy())
+            .unwrap()
+            .tokens;
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].display_name(), "GitLab");
+
+        let names = vec!["Missing".to_string()];
+        assert!(matches!(
+            export_config_tokens(&config, &names, &dir, resolve),
+            Err(Error::TokenNotFound(_))
+        ));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_build_otpauth_url_round_trip() {
+        let token = TokenData {
+            name: "john doe@example.com".to_string(),
+            secret: "HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ".to_string(),
+            issuer: Some("ACME Co".to_string()),
+            algorithm: Some(Algorithm::Sha256),
+            digits: Some(8),
+            period: Some(60),
+            ..Default::default()
+        };
+
+        let url = build_otpauth_url(&token);
+        assert_eq!(
+            url,
+            "otpauth://totp/ACME%20Co:john%20doe%40example.com\
+             ?secret=HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ&issuer=ACME%20Co\
+             &algorithm=SHA256&digits=8&period=60"
+        );
+        assert_eq!(parse_otpauth_url(&url).unwrap(), token);
+    }
+
+    #[test]
+    fn test_build_otpauth_url_hotp_defaults() {
+        let token = TokenData {
+            name: "vpn".to_string(),
+            secret: "JBSWY3DPEHPK3PXP".to_string(),
+            token_type: TokenType::Hotp,
+            counter: Some(9),
+            ..Default::default()
+        };
+
+        let url = build_otpauth_url(&token);
+        assert_eq!(
+            url,
+            "otpauth://hotp/vpn?secret=JBSWY3DPEHPK3PXP&algorithm=SHA1&digits=6&counter=9"
+        );
+
+        let parsed = parse_otpauth_url(&url).unwrap();
+        assert_eq!(parsed.token_type, TokenType::Hotp);
+        assert_eq!(parsed.counter, Some(9));
+    }
 }
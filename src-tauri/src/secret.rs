@@ -0,0 +1,203 @@
+use crate::config::TokenData;
+use crate::error::{Error, Result};
+use std::fs;
+use std::process::Command;
+
+/// Whether the secret of a token lives outside the config file
+pub fn is_external(token: &TokenData) -> bool {
+    token.secret_command.is_some() || token.secret_file.is_some() || token.secret_env.is_some()
+}
+
+/// Check that exactly one source is configured for the secret of a token
+pub fn check_sources(token: &TokenData) -> Result<()> {
+    let sources = [
+        !token.secret.is_empty(),
+        token.secret_command.is_some(),
+        token.secret_file.is_some(),
+        token.secret_env.is_some(),
+    ];
+    match sources.iter().filter(|set| **set).count() {
+        0 => Err(Error::SecretSource(
+            "No secret, set one of secret, secret_command, secret_file or secret_env".to_string(),
+        )),
+        1 => Ok(()),
+        _ => Err(Error::SecretSource(
+            "Only one of secret, secret_command, secret_file and secret_env may be set".to_string(),
+        )),
+    }
+}
+
+/// Resolve the base32 secret of a token from the config, a command, a file or
+/// an environment variable. Exactly one of these sources must be configured.
+pub fn resolve_secret(token: &TokenData) -> Result<String> {
+    check_sources(token)?;
+
+    let raw = if let Some(command) = &token.secret_command {
+        run_secret_command(command)?
+    } else if let Some(path) = &token.secret_file {
+        let path = expand_home(path);
+        fs::read_to_string(&path).map_err(|e| Error::io("read secret file", path, e))?
+    } else if let Some(name) = &token.secret_env {
+        std::env::var(name).map_err(|source| Error::SecretEnv {
+            name: name.clone(),
+            source,
+        })?
+    } else {
+        token.secret.clone()
+    };
+
+    let secret = normalize_secret(&raw)?;
+    if secret.is_empty() {
+        return Err(Error::SecretSource("Secret is empty".to_string()));
+    }
+
+    Ok(secret)
+}
+
+fn run_secret_command(command: &[String]) -> Result<String> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| Error::SecretSource("secret_command is empty".to_string()))?;
+
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|source| Error::SecretCommand {
+            program: program.clone(),
+            source,
+        })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.lines().next().unwrap_or("").trim();
+        return Err(Error::SecretCommandFailed {
+            command: command.join(" "),
+            status: output.status,
+            stderr: (!reason.is_empty()).then(|| reason.to_string()),
+        });
+    }
+
+    String::from_utf8(output.stdout).map_err(|source| Error::SecretCommandOutput {
+        command: command.join(" "),
+        source,
+    })
+}
+
+/// Use the first line of the output, which is where `pass` keeps the password.
+/// An `otpauth://` URI, as stored by pass-otp, is reduced to its secret.
+fn normalize_secret(raw: &str) -> Result<String> {
+    let line = raw.lines().next().unwrap_or("").trim();
+
+    let secret = if line.starts_with("otpauth://") {
+        let url = url::Url::parse(line)?;
+        url.query_pairs()
+            .find(|(key, _)| key == "secret")
+            .map(|(_, value)| value.to_string())
+            .ok_or_else(|| Error::SecretSource("No secret found in URL".to_string()))?
+    } else {
+        line.to_string()
+    };
+
+    Ok(secret
+        .chars()
+        .filter(|c| *c != '=' && !c.is_whitespace())
+        .collect())
+}
+
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_inline_secret() {
+        let token = TokenData {
+            secret: "JBSW Y3DP EHPK 3PXP".to_string(),
+            ..Default::default()
+        };
+
+        assert!(!is_external(&token));
+        assert_eq!(resolve_secret(&token).unwrap(), "JBSWY3DPEHPK3PXP");
+    }
+
+    #[test]
+    fn test_resolve_secret_env() {
+        std::env::set_var("OTP_BAR_TEST_SECRET_ENV", "JBSWY3DPEHPK3PXP\n");
+        let token = TokenData {
+            secret_env: Some("OTP_BAR_TEST_SECRET_ENV".to_string()),
+            ..Default::default()
+        };
+
+        assert!(is_external(&token));
+        assert_eq!(resolve_secret(&token).unwrap(), "JBSWY3DPEHPK3PXP");
+
+        let missing = TokenData {
+            secret_env: Some("OTP_BAR_TEST_SECRET_ENV_MISSING".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            resolve_secret(&missing),
+            Err(Error::SecretEnv { name, .. }) if name == "OTP_BAR_TEST_SECRET_ENV_MISSING"
+        ));
+    }
+
+    #[test]
+    fn test_resolve_secret_file() {
+        let path = std::env::temp_dir().join("otp_bar_test_secret_file");
+        fs::write(
+            &path,
+            "otpauth://totp/alice?secret=JBSWY3DPEHPK3PXP&issuer=Example\nlogin: alice\n",
+        )
+        .unwrap();
+
+        let token = TokenData {
+            secret_file: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolve_secret(&token).unwrap(), "JBSWY3DPEHPK3PXP");
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_secret_command() {
+        let token = TokenData {
+            secret_command: Some(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "echo JBSWY3DPEHPK3PXP".to_string(),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(resolve_secret(&token).unwrap(), "JBSWY3DPEHPK3PXP");
+
+        let failing = TokenData {
+            secret_command: Some(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "echo 'entry not found' >&2; exit 1".to_string(),
+            ]),
+            ..Default::default()
+        };
+        let err = resolve_secret(&failing).unwrap_err();
+        assert!(err.to_string().contains("entry not found"), "{}", err);
+    }
+
+    #[test]
+    fn test_resolve_secret_conflicting_sources() {
+        let token = TokenData {
+            secret: "JBSWY3DPEHPK3PXP".to_string(),
+            secret_env: Some("OTP_BAR_TEST_SECRET_ENV".to_string()),
+            ..Default::default()
+        };
+        assert!(resolve_secret(&token).is_err());
+        assert!(resolve_secret(&TokenData::default()).is_err());
+    }
+}
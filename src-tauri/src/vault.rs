@@ -0,0 +1,256 @@
+use crate::error::{Error, Result};
+use argon2::{Algorithm as Argon2Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+const VAULT_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// Environment variable that supplies the vault password without prompting
+pub const PASSWORD_ENV: &str = "OTP_BAR_PASSWORD";
+
+/// On-disk format of the encrypted config. Only the ciphertext is secret,
+/// the key derivation parameters are stored so they can be raised later.
+#[derive(Debug, Serialize, Deserialize)]
+struct VaultFile {
+    version: u32,
+    kdf: String,
+    memory_cost: u32,
+    time_cost: u32,
+    parallelism: u32,
+    salt: String,
+    cipher: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Key derived from the vault password. Kept in memory while the app runs
+/// so the config can be saved again without asking for the password.
+#[derive(Clone)]
+pub struct VaultKey {
+    key: [u8; KEY_LEN],
+    salt: [u8; SALT_LEN],
+    params: Params,
+}
+
+impl std::fmt::Debug for VaultKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VaultKey").finish_non_exhaustive()
+    }
+}
+
+impl VaultKey {
+    /// Derive a key for a new vault with a fresh random salt
+    pub fn new(password: &str) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::derive(password, salt, Params::default())
+    }
+
+    fn derive(password: &str, salt: [u8; SALT_LEN], params: Params) -> Result<Self> {
+        let mut key = [0u8; KEY_LEN];
+        Argon2::new(Argon2Algorithm::Argon2id, Version::V0x13, params.clone())
+            .hash_password_into(password.as_bytes(), &salt, &mut key)
+            .map_err(Error::VaultKey)?;
+
+        Ok(VaultKey { key, salt, params })
+    }
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>> {
+    Ok(base64::engine::general_purpose::STANDARD.decode(value)?)
+}
+
+/// Encrypt `plaintext` and return the content of the vault file
+pub fn encrypt(plaintext: &[u8], key: &VaultKey) -> Result<String> {
+    let cipher = XChaCha20Poly1305::new(&key.key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(Error::VaultEncrypt)?;
+
+    let engine = base64::engine::general_purpose::STANDARD;
+    let file = VaultFile {
+        version: VAULT_VERSION,
+        kdf: "argon2id".to_string(),
+        memory_cost: key.params.m_cost(),
+        time_cost: key.params.t_cost(),
+        parallelism: key.params.p_cost(),
+        salt: engine.encode(key.salt),
+        cipher: "xchacha20poly1305".to_string(),
+        nonce: engine.encode(nonce),
+        ciphertext: engine.encode(ciphertext),
+    };
+
+    Ok(toml::to_string_pretty(&file)?)
+}
+
+fn parse_vault_file(content: &str) -> Result<(VaultFile, [u8; SALT_LEN], Params)> {
+    let file: VaultFile = toml::from_str(content).map_err(Error::VaultParse)?;
+
+    if file.version != VAULT_VERSION || file.kdf != "argon2id" || file.cipher != "xchacha20poly1305"
+    {
+        return Err(Error::InvalidVault(format!(
+            "Unsupported vault format (version {}, {}, {})",
+            file.version, file.kdf, file.cipher
+        )));
+    }
+
+    let salt: [u8; SALT_LEN] = decode_base64(&file.salt)?
+        .try_into()
+        .map_err(|_| Error::InvalidVault("Invalid salt length in vault".to_string()))?;
+    let params = Params::new(
+        file.memory_cost,
+        file.time_cost,
+        file.parallelism,
+        Some(KEY_LEN),
+    )
+    .map_err(Error::VaultParams)?;
+
+    Ok((file, salt, params))
+}
+
+fn decrypt_file(file: &VaultFile, key: &VaultKey) -> Result<Vec<u8>> {
+    let nonce = decode_base64(&file.nonce)?;
+    if nonce.len() != 24 {
+        return Err(Error::InvalidVault(
+            "Invalid nonce length in vault".to_string(),
+        ));
+    }
+    let ciphertext = decode_base64(&file.ciphertext)?;
+
+    XChaCha20Poly1305::new(&key.key.into())
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(Error::WrongPassword)
+}
+
+/// Decrypt the content of a vault file with the password.
+/// Returns the plaintext and the key to encrypt it again with.
+pub fn decrypt(content: &str, password: &str) -> Result<(Vec<u8>, VaultKey)> {
+    let (file, salt, params) = parse_vault_file(content)?;
+    let key = VaultKey::derive(password, salt, params)?;
+    let plaintext = decrypt_file(&file, &key)?;
+
+    Ok((plaintext, key))
+}
+
+/// Decrypt the content of a vault file with an already derived key
+pub fn decrypt_with_key(content: &str, key: &VaultKey) -> Result<Vec<u8>> {
+    let (file, salt, _) = parse_vault_file(content)?;
+    if salt != key.salt {
+        return Err(Error::InvalidVault(
+            "Vault was re-encrypted with another password".to_string(),
+        ));
+    }
+
+    decrypt_file(&file, key)
+}
+
+/// Ask the user for the vault password. Returns `None` if the prompt was cancelled.
+///
+/// The `OTP_BAR_PASSWORD` environment variable takes precedence over prompting.
+pub fn prompt_password(message: &str) -> Result<Option<String>> {
+    if let Ok(password) = std::env::var(PASSWORD_ENV) {
+        return Ok(Some(password));
+    }
+
+    #[cfg(target_os = "macos")]
+    let output = Command::new("osascript")
+        .arg("-e")
+        .arg(format!(
+            "display dialog \"{}\" default answer \"\" with hidden answer with title \"OTP Bar\"",
+            message.replace('\\', "\\\\").replace('"', "\\\"")
+        ))
+        .arg("-e")
+        .arg("text returned of result")
+        .output();
+
+    #[cfg(not(target_os = "macos"))]
+    let output = Command::new("zenity")
+        .args(["--password", "--title", message])
+        .output();
+
+    let output = output.map_err(|source| Error::PasswordPrompt { source })?;
+
+    // Both osascript and zenity exit with status 1 when cancelled
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let password = String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches(['\r', '\n'])
+        .to_string();
+    Ok(Some(password))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let key = VaultKey::new("correct horse").unwrap();
+        let content = encrypt(b"[tokens.github]\nsecret = \"JBSWY3DPEHPK3PXP\"\n", &key).unwrap();
+
+        assert!(!content.contains("JBSWY3DPEHPK3PXP"));
+
+        let (plaintext, reopened) = decrypt(&content, "correct horse").unwrap();
+        assert_eq!(
+            plaintext,
+            b"[tokens.github]\nsecret = \"JBSWY3DPEHPK3PXP\"\n".to_vec()
+        );
+        assert_eq!(reopened.key, key.key);
+    }
+
+    #[test]
+    fn test_decrypt_wrong_password() {
+        let key = VaultKey::new("correct horse").unwrap();
+        let content = encrypt(b"secret", &key).unwrap();
+
+        assert!(matches!(
+            decrypt(&content, "battery staple"),
+            Err(Error::WrongPassword(_))
+        ));
+    }
+
+    #[test]
+    fn test_decrypt_tampered_ciphertext() {
+        let key = VaultKey::new("correct horse").unwrap();
+        let mut file: VaultFile = toml::from_str(&encrypt(b"secret", &key).unwrap()).unwrap();
+
+        let engine = base64::engine::general_purpose::STANDARD;
+        let mut ciphertext = engine.decode(&file.ciphertext).unwrap();
+        ciphertext[0] ^= 0x01;
+        file.ciphertext = engine.encode(ciphertext);
+
+        let content = toml::to_string(&file).unwrap();
+        assert!(decrypt(&content, "correct horse").is_err());
+    }
+
+    #[test]
+    fn test_nonce_is_fresh_per_save() {
+        let key = VaultKey::new("correct horse").unwrap();
+        assert_ne!(
+            encrypt(b"secret", &key).unwrap(),
+            encrypt(b"secret", &key).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decrypt_with_key() {
+        let key = VaultKey::new("correct horse").unwrap();
+        let content = encrypt(b"secret", &key).unwrap();
+        assert_eq!(
+            decrypt_with_key(&content, &key).unwrap(),
+            b"secret".to_vec()
+        );
+
+        let other = VaultKey::new("correct horse").unwrap();
+        assert!(decrypt_with_key(&content, &other).is_err());
+    }
+}
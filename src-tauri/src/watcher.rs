@@ -0,0 +1,93 @@
+use crate::error::{Error, Result};
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long a file must be left alone before a change is reported.
+/// Editors often save in several steps (write a temp file, rename, chmod).
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Call `on_change` after any of `files` was written, created, replaced or removed.
+///
+/// The parent directories are watched rather than the files themselves, so a
+/// file that an editor replaces by renaming a new one over it is still tracked.
+/// The watch stops when the returned debouncer is dropped.
+pub fn watch_files<F>(files: &[PathBuf], mut on_change: F) -> Result<Debouncer<RecommendedWatcher>>
+where
+    F: FnMut() + Send + 'static,
+{
+    let names: Vec<OsString> = files
+        .iter()
+        .filter_map(|file| file.file_name().map(|name| name.to_os_string()))
+        .collect();
+
+    let mut debouncer = new_debouncer(DEBOUNCE, move |result: DebounceEventResult| {
+        match result {
+            Ok(events) => {
+                // Only the watched directories are reported, so the name is enough
+                let changed = events.iter().any(|event| {
+                    event
+                        .path
+                        .file_name()
+                        .is_some_and(|name| names.iter().any(|watched| watched == name))
+                });
+                if changed {
+                    on_change();
+                }
+            }
+            Err(e) => eprintln!("Failed to watch config: {}", e),
+        }
+    })
+    .map_err(Error::Watcher)?;
+
+    let mut dirs: Vec<&Path> = files.iter().filter_map(|file| file.parent()).collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        debouncer
+            .watcher()
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(|source| Error::Watch {
+                path: dir.to_path_buf(),
+                source,
+            })?;
+    }
+
+    Ok(debouncer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_watch_files_reports_replaced_file() {
+        let dir = std::env::temp_dir().join("otp_bar_test_watcher");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.toml");
+        fs::write(&config_path, "").unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let _debouncer = watch_files(std::slice::from_ref(&config_path), move || {
+            let _ = sender.send(());
+        })
+        .unwrap();
+
+        // Other files in the directory are ignored
+        fs::write(dir.join("other.txt"), "x").unwrap();
+        assert!(receiver.recv_timeout(Duration::from_secs(2)).is_err());
+
+        // Replace the config the way many editors save
+        let temp_path = dir.join("config.toml.tmp");
+        fs::write(&temp_path, "[tokens]\n").unwrap();
+        fs::rename(&temp_path, &config_path).unwrap();
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,57 @@
+//! Runs the `otp-bar` binary the way scripts do, with its own home folder
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn otp_bar(home: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_otp-bar"))
+        .args(args)
+        .env("HOME", home)
+        .env_remove("OTP_BAR_PASSWORD")
+        .output()
+        .unwrap()
+}
+
+/// Stdout of a successful command, which must be a single JSON document
+fn json_stdout(output: Output) -> (String, serde_json::Value) {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let value = serde_json::from_str(&stdout)
+        .unwrap_or_else(|e| panic!("stdout is not only JSON ({}): {}", e, stdout));
+    (stdout, value)
+}
+
+#[test]
+fn test_stdout_is_only_json() {
+    let home = std::env::temp_dir().join("otp_bar_test_cli_stdout");
+    let _ = std::fs::remove_dir_all(&home);
+    std::fs::create_dir_all(&home).unwrap();
+
+    let (_, added) = json_stdout(otp_bar(&home, &["add", "GitHub", "JBSWY3DPEHPK3PXP"]));
+    assert_eq!(added["added"], "GitHub");
+
+    let (_, list) = json_stdout(otp_bar(&home, &["list"]));
+    assert_eq!(list[0]["name"], "GitHub");
+
+    let (_, code) = json_stdout(otp_bar(&home, &["code", "GitHub"]));
+    assert_eq!(code["code"].as_str().unwrap().len(), 6);
+
+    // Importing must not echo the secrets in the QR code
+    let image = home.join("qr.png");
+    qrcode::QrCode::new(b"otpauth://totp/Example:alice?secret=GEZDGNBVGY3TQOJQ&issuer=Example")
+        .unwrap()
+        .render::<image::Luma<u8>>()
+        .min_dimensions(300, 300)
+        .build()
+        .save(&image)
+        .unwrap();
+    let (stdout, import) = json_stdout(otp_bar(&home, &["import", &image.to_string_lossy()]));
+    assert_eq!(import["tokens"][0]["name"], "Example (alice)");
+    assert!(!stdout.contains("GEZDGNBVGY3TQOJQ"));
+
+    let _ = std::fs::remove_dir_all(&home);
+}